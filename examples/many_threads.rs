@@ -14,7 +14,7 @@ fn main() {
                 eprintln!("Thread {} started", thread);
                 let mut rng = rand::thread_rng();
                 for i in 0..1_000_000 {
-                    if let None = numbers.get(&i) {
+                    if numbers.get(&i).is_none() {
                         numbers.insert(i, rng.gen());
                     }
                     if i % 10_000 == 0 {
@@ -1,53 +1,443 @@
-use core::num::NonZeroUsize;
+#[cfg(feature = "std")]
+use alloc::collections::BTreeSet;
+use alloc::{boxed::Box, collections::BTreeMap, vec::Vec};
+use core::{
+    hash::{BuildHasherDefault, Hasher},
+    num::NonZeroUsize,
+};
 use lru::LruCache;
 use rand::{rngs::SmallRng, Rng, SeedableRng};
+#[cfg(feature = "std")]
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
 
-pub(crate) struct Allocator {
+type Callback = Box<dyn FnMut() + Send>;
+
+/// How far usage must fall back below a threshold before it can fire again.
+const THRESHOLD_HYSTERESIS: f32 = 0.05;
+
+/// Byte accounting and LRU victim selection for a pool of entries, without storing any values.
+///
+/// This is what [`SharedLru`](crate::SharedLru) uses under its lock, and is available on its own in
+/// `no_std` builds.
+pub struct Allocator {
     used: usize,
     capacity: usize,
     evicting: bool,
+    eviction_starts: u64,
     rng: SmallRng,
-    allocated: LruCache<EntryId, usize>,
+    allocated: LruCache<EntryId, usize, IdBuildHasher>,
+    /// How many times each pinned entry has been pinned.
+    pinned: BTreeMap<EntryId, usize>,
+    /// The members of each group, and the group of each member.
+    groups: BTreeMap<u64, Vec<EntryId>>,
+    group_of: BTreeMap<EntryId, u64>,
+    max_entries: Option<usize>,
+    low_watermark: f32,
+    on_eviction_start: Option<Callback>,
+    on_eviction_stop: Option<Callback>,
+    thresholds: Vec<UsageThreshold>,
+    #[cfg(feature = "std")]
+    residency: Option<Residency>,
+    #[cfg(feature = "std")]
+    aging: Option<Aging>,
+}
+
+struct UsageThreshold {
+    fraction: f32,
+    armed: bool,
+    callback: Callback,
+}
+
+/// Access counts that halve every `half_life`, for evicting the least frequently used entry.
+///
+/// Decay is the same for every entry, so rather than updating counts as time passes each entry
+/// is ordered by `log2(count) + t / half_life` as of its last access. That key never changes
+/// until the next access, and the smallest key is always the entry with the lowest count now.
+#[cfg(feature = "std")]
+struct Aging {
+    half_life: Duration,
+    epoch: Instant,
+    keys: HashMap<EntryId, u64, IdBuildHasher>,
+    order: BTreeSet<(u64, EntryId)>,
+}
+
+#[cfg(feature = "std")]
+impl Aging {
+    fn record_access(&mut self, id: EntryId) {
+        let now = self.epoch.elapsed().as_secs_f64() / self.half_life.as_secs_f64();
+        let count = match self.keys.get(&id) {
+            Some(&key) => {
+                self.order.remove(&(key, id));
+                (f64::from_bits(key) - now).exp2()
+            }
+            None => 0.,
+        };
+        // Counts are at least 1 after an access, so keys are positive and their bit patterns
+        // sort the same as their values.
+        let key = ((count + 1.).log2() + now).to_bits();
+        self.keys.insert(id, key);
+        self.order.insert((key, id));
+    }
+
+    fn remove(&mut self, id: EntryId) {
+        if let Some(key) = self.keys.remove(&id) {
+            self.order.remove(&(key, id));
+        }
+    }
+}
+
+/// Entries are spared from eviction until they have been allocated for `min`.
+#[cfg(feature = "std")]
+struct Residency {
+    min: Duration,
+    allocated_at: HashMap<EntryId, Instant, IdBuildHasher>,
 }
 
 impl Allocator {
-    pub(crate) fn new(capacity: usize) -> Self {
+    /// An empty allocator holding at most `capacity` bytes.
+    #[cfg(feature = "std")]
+    pub fn new(capacity: usize) -> Self {
+        Self::with_rng(capacity, SmallRng::from_entropy())
+    }
+
+    /// Like [`Allocator::new`], but with deterministic ids, and without needing an entropy source.
+    pub fn with_seed(capacity: usize, seed: u64) -> Self {
+        Self::with_rng(capacity, SmallRng::seed_from_u64(seed))
+    }
+
+    fn with_rng(capacity: usize, rng: SmallRng) -> Self {
         Allocator {
             used: 0,
             capacity,
             evicting: false,
-            allocated: LruCache::unbounded(),
-            rng: SmallRng::from_entropy(),
+            eviction_starts: 0,
+            allocated: LruCache::unbounded_with_hasher(IdBuildHasher::default()),
+            pinned: BTreeMap::new(),
+            groups: BTreeMap::new(),
+            group_of: BTreeMap::new(),
+            max_entries: None,
+            low_watermark: 7. / 8.,
+            rng,
+            on_eviction_start: None,
+            on_eviction_stop: None,
+            thresholds: Vec::new(),
+            #[cfg(feature = "std")]
+            residency: None,
+            #[cfg(feature = "std")]
+            aging: None,
         }
     }
 
-    pub(crate) fn try_alloc(&mut self, bytes: usize) -> AllocResult {
-        if bytes > self.capacity {
+    /// Builds an allocator with a fixed RNG seed and `entries` already allocated, oldest first,
+    /// so tests can assert on specific ids.
+    #[cfg(test)]
+    pub(crate) fn from_parts(
+        capacity: usize,
+        entries: impl IntoIterator<Item = (EntryId, usize)>,
+    ) -> Self {
+        let mut allocator = Allocator::with_seed(capacity, 0);
+        for (id, bytes) in entries {
+            allocator.allocated.put(id, bytes);
+            allocator.used += bytes;
+        }
+        allocator
+    }
+
+    /// Tries to allocate `bytes`. If room must be made first, returns the oldest entry not in
+    /// `skip` to evict, falling back to the oldest overall. The caller frees it and tries again.
+    pub fn try_alloc(&mut self, bytes: usize, skip: &[EntryId]) -> AllocResult {
+        self.try_alloc_with(bytes, skip, false)
+    }
+
+    /// Like [`Allocator::try_alloc`], but only asks for evictions while `bytes` doesn't fit,
+    /// rather than down to the low watermark. For callers that budgeted the evictions with
+    /// [`Allocator::eviction_cost`].
+    pub fn try_alloc_exact(&mut self, bytes: usize) -> AllocResult {
+        self.try_alloc_with(bytes, &[], true)
+    }
+
+    fn try_alloc_with(&mut self, bytes: usize, skip: &[EntryId], exact: bool) -> AllocResult {
+        if bytes > self.capacity || self.max_entries == Some(0) {
             return AllocResult::TooLarge;
         }
 
-        if self.used + bytes > self.capacity {
+        if self.needs_room(bytes) {
             if !self.evicting {
                 log::info!("Beginning eviction, {}% used", self.percent_used() * 100.);
+                self.eviction_starts += 1;
+                if let Some(callback) = &mut self.on_eviction_start {
+                    callback();
+                }
             }
             self.evicting = true;
-        } else if self.used < (self.capacity / 8 * 7) {
+        } else if self.used < (self.capacity as f32 * self.low_watermark) as usize {
             if self.evicting {
                 log::info!("Finished evicting, {}% used", self.percent_used() * 100.);
+                if let Some(callback) = &mut self.on_eviction_stop {
+                    callback();
+                }
             }
             self.evicting = false;
         }
 
-        if self.evicting {
-            let (id, bytes) = self.allocated.pop_lru().expect("should have item");
-            self.used -= bytes;
-            return AllocResult::Evict(id);
+        if self.evicting && (!exact || self.needs_room(bytes)) {
+            match self.next_victim(skip).or_else(|| self.oldest()) {
+                Some(id) => return AllocResult::Evict(id),
+                // Nothing left can be evicted: everything is pinned, or `used` drifted above
+                // what the entries account for. Evicting can't help, so give up rather than loop.
+                None if self.needs_room(bytes) => return AllocResult::TooLarge,
+                None => {}
+            }
         }
 
+        let id = self.alloc(bytes);
+        self.check_thresholds();
+        AllocResult::Success(id)
+    }
+
+    /// The entry to evict next, sparing young entries while older ones remain.
+    fn next_victim(&self, skip: &[EntryId]) -> Option<EntryId> {
+        let candidates = || {
+            self.victims()
+                .map(|(id, _)| id)
+                .filter(|id| !skip.contains(id))
+        };
+        candidates()
+            .find(|id| !self.is_young(*id))
+            .or_else(|| candidates().next())
+    }
+
+    fn check_thresholds(&mut self) {
+        let percent_used = self.percent_used();
+        for threshold in &mut self.thresholds {
+            if threshold.armed && percent_used >= threshold.fraction {
+                threshold.armed = false;
+                (threshold.callback)();
+            } else if percent_used < threshold.fraction - THRESHOLD_HYSTERESIS {
+                threshold.armed = true;
+            }
+        }
+    }
+
+    /// Allocates without checking capacity or evicting, so `used` may exceed `capacity`
+    /// afterwards. Use [`Allocator::try_alloc`] to stay within the limit.
+    pub fn alloc(&mut self, bytes: usize) -> EntryId {
         let id = self.get_id();
         self.allocated.put(id, bytes);
         self.used += bytes;
-        AllocResult::Success(id)
+        #[cfg(feature = "std")]
+        if let Some(residency) = &mut self.residency {
+            residency.allocated_at.insert(id, Instant::now());
+        }
+        #[cfg(feature = "std")]
+        if let Some(aging) = &mut self.aging {
+            aging.record_access(id);
+        }
+        id
+    }
+
+    /// Whether `id` was allocated too recently to be evicted while older entries remain.
+    #[cfg(feature = "std")]
+    fn is_young(&self, id: EntryId) -> bool {
+        self.residency.as_ref().is_some_and(|residency| {
+            residency
+                .allocated_at
+                .get(&id)
+                .is_some_and(|at| at.elapsed() < residency.min)
+        })
+    }
+
+    #[cfg(not(feature = "std"))]
+    fn is_young(&self, _id: EntryId) -> bool {
+        false
+    }
+
+    /// Whether allocating `bytes` requires evicting something first.
+    pub fn needs_room(&self, bytes: usize) -> bool {
+        self.used + bytes > self.capacity || self.entries_over_limit() > 0
+    }
+
+    fn entries_over_limit(&self) -> usize {
+        match self.max_entries {
+            Some(max) => (self.allocated.len() + 1).saturating_sub(max),
+            None => 0,
+        }
+    }
+
+    /// How many bytes of entries would need to be evicted to fit `bytes`, picked the way
+    /// [`Allocator::try_alloc_exact`] picks them and counting the group mates they take with them,
+    /// or `None` if it can never fit.
+    pub fn eviction_cost(&self, bytes: usize) -> Option<usize> {
+        if bytes > self.capacity || self.max_entries == Some(0) {
+            return None;
+        }
+
+        let overflow = (self.used + bytes).saturating_sub(self.capacity);
+        let entries_over = self.entries_over_limit();
+        let mut cost = 0;
+        let mut evicted = Vec::new();
+        while cost < overflow || evicted.len() < entries_over {
+            let victim = self.next_victim(&evicted)?;
+            // Evicting a grouped entry takes its unpinned group mates with it.
+            let mates = self.group_mates(victim).into_iter();
+            for id in core::iter::once(victim).chain(mates.filter(|&m| !self.is_pinned(m))) {
+                if !evicted.contains(&id) {
+                    cost += self.allocated.peek(&id)?;
+                    evicted.push(id);
+                }
+            }
+        }
+        Some(cost)
+    }
+
+    /// The next entry to evict: the least recently used entry that is not pinned, or the least
+    /// frequently used with [`Allocator::set_aging_half_life`].
+    pub fn oldest(&self) -> Option<EntryId> {
+        self.victims().next().map(|(id, _)| id)
+    }
+
+    /// Frees a specific entry, returning how many bytes it held.
+    pub fn free(&mut self, id: EntryId) -> Option<usize> {
+        let bytes = self.allocated.pop(&id)?;
+        self.used -= bytes;
+        self.pinned.remove(&id);
+        self.leave_group(id);
+        #[cfg(feature = "std")]
+        if let Some(residency) = &mut self.residency {
+            residency.allocated_at.remove(&id);
+        }
+        #[cfg(feature = "std")]
+        if let Some(aging) = &mut self.aging {
+            aging.remove(id);
+        }
+        Some(bytes)
+    }
+
+    /// Whether `id` is allocated.
+    pub fn contains(&self, id: EntryId) -> bool {
+        self.allocated.contains(&id)
+    }
+
+    /// How many bytes `id` holds, or `None` if it is not allocated.
+    pub fn bytes_of(&self, id: EntryId) -> Option<usize> {
+        self.allocated.peek(&id).copied()
+    }
+
+    /// Excludes `id` from eviction until it is unpinned as many times as it was pinned, or freed.
+    /// Returns false if it is not allocated.
+    pub fn pin(&mut self, id: EntryId) -> bool {
+        if !self.allocated.contains(&id) {
+            return false;
+        }
+        *self.pinned.entry(id).or_insert(0) += 1;
+        true
+    }
+
+    /// Undoes one [`Allocator::pin`]. Does nothing if `id` is not pinned.
+    pub fn unpin(&mut self, id: EntryId) {
+        if let Some(count) = self.pinned.get_mut(&id) {
+            *count -= 1;
+            if *count == 0 {
+                self.pinned.remove(&id);
+            }
+        }
+    }
+
+    /// Adds `id` to `group`, so [`Allocator::set_newest`] on any member promotes them all, and
+    /// [`Allocator::ungroup`] gives the rest to evict along with it. Returns false if `id` is not
+    /// allocated.
+    pub fn set_group(&mut self, id: EntryId, group: u64) -> bool {
+        if !self.allocated.contains(&id) {
+            return false;
+        }
+        self.leave_group(id);
+        self.groups.entry(group).or_default().push(id);
+        self.group_of.insert(id, group);
+        true
+    }
+
+    /// The other members of `id`'s group, if it has one.
+    pub fn group_mates(&self, id: EntryId) -> Vec<EntryId> {
+        let members = match self.group_of.get(&id) {
+            Some(group) => &self.groups[group],
+            None => return Vec::new(),
+        };
+        members.iter().copied().filter(|&m| m != id).collect()
+    }
+
+    /// Dissolves `id`'s group, returning its other members.
+    pub fn ungroup(&mut self, id: EntryId) -> Vec<EntryId> {
+        let mates = self.group_mates(id);
+        for member in mates.iter().chain([&id]) {
+            self.leave_group(*member);
+        }
+        mates
+    }
+
+    /// Whether `id` is excluded from eviction by [`Allocator::pin`].
+    pub fn is_pinned(&self, id: EntryId) -> bool {
+        self.pinned.contains_key(&id)
+    }
+
+    fn leave_group(&mut self, id: EntryId) {
+        let group = match self.group_of.remove(&id) {
+            Some(group) => group,
+            None => return,
+        };
+        let members = self
+            .groups
+            .get_mut(&group)
+            .expect("group of a member should exist");
+        members.retain(|&m| m != id);
+        if members.is_empty() {
+            self.groups.remove(&group);
+        }
+    }
+
+    /// Changes the recorded size of `id`, returning its previous size.
+    pub fn resize(&mut self, id: EntryId, bytes: usize) -> Option<usize> {
+        let entry = self.allocated.peek_mut(&id)?;
+        let old = core::mem::replace(entry, bytes);
+        self.used = self.used - old + bytes;
+        Some(old)
+    }
+
+    /// Shrinks internal storage to fit the current entries, keeping their order.
+    pub fn compact(&mut self) {
+        let len = self.allocated.len();
+        // Resizing to the current length shrinks the map without dropping anything.
+        self.allocated.resize(len);
+        self.allocated.resize(usize::MAX);
+    }
+
+    /// Panics if `used` or the pins disagree with the allocated entries.
+    #[cfg(all(test, feature = "std"))]
+    pub(crate) fn debug_invariants(&self) {
+        let total = self.oldest_first().map(|(_, bytes)| bytes).sum::<usize>();
+        assert_eq!(
+            self.used, total,
+            "used does not match the allocated entries"
+        );
+        for id in self.pinned.keys() {
+            assert!(self.contains(*id), "{:?} is pinned but not allocated", id);
+        }
+        for (id, group) in &self.group_of {
+            assert!(self.contains(*id), "{:?} is grouped but not allocated", id);
+            assert!(
+                self.groups[group].contains(id),
+                "{:?} missing from its group",
+                id
+            );
+        }
+    }
+
+    /// A random seed derived from the allocator's RNG.
+    #[cfg(feature = "std")]
+    pub(crate) fn next_seed(&mut self) -> u64 {
+        self.rng.gen()
     }
 
     fn get_id(&mut self) -> EntryId {
@@ -61,21 +451,408 @@ impl Allocator {
         }
     }
 
-    pub(crate) fn set_newest(&mut self, id: EntryId) {
-        self.allocated.get(&id);
+    /// Every allocated entry and its size, from least to most recently used.
+    pub fn oldest_first(&self) -> impl Iterator<Item = (EntryId, usize)> + '_ {
+        self.allocated.iter().rev().map(|(id, bytes)| (*id, *bytes))
+    }
+
+    /// How many entries are older than `id`, so 0 is the least recently used. Linear in the
+    /// number of entries.
+    pub fn recency_rank(&self, id: EntryId) -> Option<usize> {
+        self.oldest_first().position(|(entry, _)| entry == id)
+    }
+
+    /// Entries that may be evicted, in the order they would be: least recently used first, or
+    /// least frequently used with [`Allocator::set_aging_half_life`].
+    pub fn victims(&self) -> impl Iterator<Item = (EntryId, usize)> + '_ {
+        let order: Box<dyn Iterator<Item = (EntryId, usize)> + '_> = match self.frequency_order() {
+            Some(order) => order,
+            None => Box::new(self.oldest_first()),
+        };
+        order.filter(move |(id, _)| !self.pinned.contains_key(id))
+    }
+
+    #[cfg(feature = "std")]
+    fn frequency_order(&self) -> Option<Box<dyn Iterator<Item = (EntryId, usize)> + '_>> {
+        let aging = self.aging.as_ref()?;
+        Some(Box::new(aging.order.iter().filter_map(move |(_, id)| {
+            Some((*id, *self.allocated.peek(id)?))
+        })))
+    }
+
+    #[cfg(not(feature = "std"))]
+    fn frequency_order(&self) -> Option<Box<dyn Iterator<Item = (EntryId, usize)> + '_>> {
+        None
+    }
+
+    /// Marks `id` and the rest of its group as most recently used, returning the size of `id` if
+    /// it is still allocated.
+    pub fn set_newest(&mut self, id: EntryId) -> Option<usize> {
+        for mate in self.group_mates(id) {
+            self.touch(mate);
+        }
+        self.touch(id)
+    }
+
+    fn touch(&mut self, id: EntryId) -> Option<usize> {
+        let bytes = self.allocated.get(&id).copied()?;
+        #[cfg(feature = "std")]
+        if let Some(aging) = &mut self.aging {
+            aging.record_access(id);
+        }
+        Some(bytes)
+    }
+
+    /// Evicts the least frequently used entries first instead of the least recently used, with
+    /// access counts halving every `half_life` so entries that were only popular long ago don't
+    /// stay forever. Every allocation and [`Allocator::set_newest`] counts as an access.
+    ///
+    /// Entries allocated before this is set rank below every entry accessed since, so they are
+    /// evicted first.
+    #[cfg(feature = "std")]
+    pub fn set_aging_half_life(&mut self, half_life: Option<Duration>) {
+        self.aging = half_life.map(|half_life| {
+            // The lowest key any access can produce, as if each was accessed once at the epoch.
+            let key = 0f64.to_bits();
+            let live = self.allocated.iter().map(|(&id, _)| id);
+            Aging {
+                half_life,
+                epoch: Instant::now(),
+                keys: live.clone().map(|id| (id, key)).collect(),
+                order: live.map(|id| (key, id)).collect(),
+            }
+        });
+    }
+
+    /// Runs `callback` each time the allocator starts evicting.
+    pub fn set_on_eviction_start(&mut self, callback: impl FnMut() + Send + 'static) {
+        self.on_eviction_start = Some(Box::new(callback));
+    }
+
+    /// Runs `callback` each time the allocator drops below its low watermark and stops evicting.
+    pub fn set_on_eviction_stop(&mut self, callback: impl FnMut() + Send + 'static) {
+        self.on_eviction_stop = Some(Box::new(callback));
+    }
+
+    /// Runs `callback` when an allocation takes usage to `fraction` of capacity or more. It fires
+    /// again only after usage has dropped 5 percentage points below `fraction`.
+    pub fn add_usage_threshold(&mut self, fraction: f32, callback: impl FnMut() + Send + 'static) {
+        self.thresholds.push(UsageThreshold {
+            fraction,
+            armed: true,
+            callback: Box::new(callback),
+        });
+    }
+
+    /// Caps how many entries may be allocated at once, evicting to stay under it like the byte
+    /// limit. `None` removes the cap.
+    pub fn set_max_entries(&mut self, max_entries: Option<usize>) {
+        self.max_entries = max_entries;
+    }
+
+    /// Prefers evicting entries allocated at least `min_residency` ago, so a burst of inserts
+    /// doesn't push out what was just inserted. When every candidate is younger, the oldest is
+    /// evicted anyway.
+    ///
+    /// Entries allocated before this is set count as old enough.
+    #[cfg(feature = "std")]
+    pub fn set_min_residency(&mut self, min_residency: Option<Duration>) {
+        self.residency = min_residency.map(|min| Residency {
+            min,
+            allocated_at: HashMap::default(),
+        });
+    }
+
+    /// Once evicting, the allocator stays in eviction mode until usage drops below this fraction
+    /// of the capacity. Defaults to 7/8.
+    pub fn set_low_watermark(&mut self, low_watermark: f32) {
+        self.low_watermark = low_watermark;
+    }
+
+    /// Bytes currently allocated.
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    /// The byte limit.
+    pub fn capacity(&self) -> usize {
+        self.capacity
     }
 
+    /// Changes the byte limit. Nothing is evicted until the next [`Allocator::try_alloc`].
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+    }
+
+    /// How many times the allocator has entered eviction mode.
+    pub fn eviction_starts(&self) -> u64 {
+        self.eviction_starts
+    }
+
+    /// Whether the allocator is in eviction mode, between passing the capacity and falling back
+    /// below the low watermark.
+    pub fn is_evicting(&self) -> bool {
+        self.evicting
+    }
+
+    /// Used bytes as a fraction of the capacity.
     pub fn percent_used(&self) -> f32 {
         self.used as f32 / self.capacity as f32
     }
 }
 
+/// The outcome of [`Allocator::try_alloc`].
 #[derive(Debug)]
-pub(crate) enum AllocResult {
+pub enum AllocResult {
+    /// The bytes were allocated to this new entry.
     Success(EntryId),
+    /// Room must be made first: free this entry and try again.
     Evict(EntryId),
+    /// The request can never fit.
     TooLarge,
 }
 
+/// Identifies a single entry within a [`SharedLru`](crate::SharedLru) pool.
 #[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord)]
 pub struct EntryId(NonZeroUsize);
+
+/// Hashes [`EntryId`]s with a single multiply instead of SipHash. Ids are random integers chosen
+/// by the pool, so there is nothing for a stronger hash to defend against.
+#[derive(Default)]
+pub(crate) struct IdHasher(u64);
+
+impl Hasher for IdHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.write_u64(u64::from(byte));
+        }
+    }
+
+    fn write_u64(&mut self, n: u64) {
+        self.0 = (self.0 ^ n).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    }
+
+    fn write_usize(&mut self, n: usize) {
+        self.write_u64(n as u64);
+    }
+
+    fn finish(&self) -> u64 {
+        // The multiply leaves the best mixed bits at the top; fold them into the bottom too.
+        self.0 ^ (self.0 >> 32)
+    }
+}
+
+pub(crate) type IdBuildHasher = BuildHasherDefault<IdHasher>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    fn id(n: usize) -> EntryId {
+        EntryId(NonZeroUsize::new(n).unwrap())
+    }
+
+    #[test]
+    fn evicts_oldest_first() {
+        let mut allocator = Allocator::from_parts(30, vec![(id(1), 10), (id(2), 10), (id(3), 10)]);
+
+        assert!(matches!(allocator.try_alloc(10, &[]), AllocResult::Evict(e) if e == id(1)));
+        allocator.free(id(1));
+        assert!(matches!(
+            allocator.try_alloc(10, &[]),
+            AllocResult::Success(_)
+        ));
+    }
+
+    #[test]
+    fn touched_entries_are_evicted_last() {
+        let mut allocator = Allocator::from_parts(30, vec![(id(1), 10), (id(2), 10), (id(3), 10)]);
+        allocator.set_newest(id(1));
+
+        assert!(matches!(allocator.try_alloc(10, &[]), AllocResult::Evict(e) if e == id(2)));
+    }
+
+    #[test]
+    fn skips_requested_victims() {
+        let mut allocator = Allocator::from_parts(30, vec![(id(1), 10), (id(2), 10), (id(3), 10)]);
+
+        let skip = [id(1)];
+        assert!(matches!(allocator.try_alloc(10, &skip), AllocResult::Evict(e) if e == id(2)));
+
+        let skip = [id(1), id(2), id(3)];
+        assert!(matches!(allocator.try_alloc(10, &skip), AllocResult::Evict(e) if e == id(1)));
+    }
+
+    #[test]
+    fn never_evicts_pinned() {
+        let mut allocator = Allocator::from_parts(20, vec![(id(1), 10), (id(2), 10)]);
+        allocator.pin(id(1));
+
+        assert!(matches!(allocator.try_alloc(10, &[]), AllocResult::Evict(e) if e == id(2)));
+        assert_eq!(allocator.eviction_cost(10), Some(10));
+        allocator.free(id(2));
+
+        assert!(matches!(
+            allocator.try_alloc(15, &[]),
+            AllocResult::TooLarge
+        ));
+        assert_eq!(allocator.eviction_cost(15), None);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn spares_young_entries() {
+        let mut allocator = Allocator::from_parts(20, vec![(id(1), 10)]);
+        allocator.set_min_residency(Some(Duration::from_secs(60)));
+        let young = allocator.alloc(10);
+        allocator.set_newest(id(1));
+
+        assert!(matches!(allocator.try_alloc(10, &[]), AllocResult::Evict(e) if e == id(1)));
+        allocator.free(id(1));
+        assert!(matches!(allocator.try_alloc(20, &[]), AllocResult::Evict(e) if e == young));
+    }
+
+    #[test]
+    fn usage_threshold_fires_once_per_crossing() {
+        use core::sync::atomic::{AtomicUsize, Ordering};
+        static FIRED: AtomicUsize = AtomicUsize::new(0);
+
+        let mut allocator = Allocator::with_seed(100, 0);
+        allocator.add_usage_threshold(0.5, || {
+            FIRED.fetch_add(1, Ordering::Relaxed);
+        });
+        let ids = (0..7)
+            .map(|_| match allocator.try_alloc(10, &[]) {
+                AllocResult::Success(id) => id,
+                other => panic!("{:?}", other),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(FIRED.load(Ordering::Relaxed), 1);
+
+        for id in &ids[..4] {
+            allocator.free(*id);
+        }
+        for _ in 0..3 {
+            allocator.try_alloc(10, &[]);
+        }
+        assert_eq!(FIRED.load(Ordering::Relaxed), 2);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn aging_evicts_least_frequent() {
+        let mut allocator = Allocator::with_seed(20, 0);
+        allocator.set_aging_half_life(Some(Duration::from_secs(3600)));
+        let popular = allocator.alloc(10);
+        let once = allocator.alloc(10);
+        for _ in 0..3 {
+            allocator.set_newest(popular);
+        }
+        allocator.set_newest(once);
+
+        assert!(matches!(allocator.try_alloc(10, &[]), AllocResult::Evict(e) if e == once));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn aging_can_evict_entries_from_before_it_was_enabled() {
+        let mut allocator = Allocator::with_seed(20, 0);
+        let first = allocator.alloc(10);
+        let second = allocator.alloc(10);
+        allocator.set_aging_half_life(Some(Duration::from_secs(3600)));
+        allocator.set_newest(second);
+
+        assert!(matches!(allocator.try_alloc(10, &[]), AllocResult::Evict(e) if e == first));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn aging_forgets_old_popularity() {
+        let mut allocator = Allocator::with_seed(20, 0);
+        allocator.set_aging_half_life(Some(Duration::from_millis(1)));
+        let old = allocator.alloc(10);
+        for _ in 0..10 {
+            allocator.set_newest(old);
+        }
+        std::thread::sleep(Duration::from_millis(30));
+        let new = allocator.alloc(10);
+
+        assert!(matches!(allocator.try_alloc(10, &[]), AllocResult::Evict(e) if e == old));
+        assert!(allocator.contains(new));
+    }
+
+    #[test]
+    fn compact_keeps_order() {
+        let mut allocator = Allocator::from_parts(30, vec![(id(1), 10), (id(2), 10), (id(3), 10)]);
+        allocator.free(id(2));
+        allocator.compact();
+
+        let order = allocator.oldest_first().collect::<Vec<_>>();
+        assert_eq!(order, vec![(id(1), 10), (id(3), 10)]);
+        assert!(matches!(allocator.try_alloc(20, &[]), AllocResult::Evict(e) if e == id(1)));
+    }
+
+    #[test]
+    fn accounting_drift_does_not_loop() {
+        let mut allocator = Allocator::from_parts(30, vec![(id(1), 10)]);
+        // A lying `MemorySize` freed less than it claimed.
+        allocator.used += 25;
+
+        assert!(matches!(allocator.try_alloc(10, &[]), AllocResult::Evict(e) if e == id(1)));
+        allocator.free(id(1));
+        assert!(matches!(
+            allocator.try_alloc(10, &[]),
+            AllocResult::TooLarge
+        ));
+    }
+
+    #[test]
+    fn resize_adjusts_used() {
+        let mut allocator = Allocator::from_parts(30, vec![(id(1), 10), (id(2), 10)]);
+
+        assert_eq!(allocator.resize(id(1), 15), Some(10));
+        assert_eq!(allocator.used(), 25);
+        assert_eq!(allocator.resize(id(3), 15), None);
+    }
+
+    #[test]
+    fn same_seed_gives_same_ids() {
+        let mut a = Allocator::from_parts(100, vec![]);
+        let mut b = Allocator::from_parts(100, vec![]);
+
+        for _ in 0..10 {
+            match (a.try_alloc(1, &[]), b.try_alloc(1, &[])) {
+                (AllocResult::Success(x), AllocResult::Success(y)) => assert_eq!(x, y),
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn id_hasher_spreads_sequential_ids() {
+        use alloc::collections::BTreeSet;
+        use core::hash::BuildHasher;
+
+        let hashes = (1..=256).map(|n| IdBuildHasher::default().hash_one(id(n)));
+        let (low, high): (BTreeSet<_>, BTreeSet<_>) = hashes.map(|h| (h & 0x7f, h >> 57)).unzip();
+
+        assert!(low.len() > 64);
+        assert!(high.len() > 64);
+    }
+
+    #[test]
+    fn touching_a_member_promotes_its_group() {
+        let mut allocator =
+            Allocator::from_parts(40, vec![(id(1), 10), (id(2), 10), (id(3), 10), (id(4), 10)]);
+        allocator.set_group(id(1), 7);
+        allocator.set_group(id(2), 7);
+        allocator.set_newest(id(2));
+
+        assert_eq!(allocator.group_mates(id(2)), vec![id(1)]);
+        assert!(matches!(allocator.try_alloc(10, &[]), AllocResult::Evict(e) if e == id(3)));
+
+        allocator.free(id(1));
+        assert!(allocator.group_mates(id(2)).is_empty());
+    }
+}
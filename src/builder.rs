@@ -0,0 +1,232 @@
+use crate::{
+    allocator::Allocator,
+    ghost::GhostList,
+    pool::{InnerShared, PoolOptions},
+    Policy, SharedLru,
+};
+use std::{fmt, sync::Arc, time::Duration};
+
+/// Configures a [`SharedLru`] before creating it.
+///
+/// ```
+/// use shared_lru::SharedLru;
+///
+/// let shared = SharedLru::builder()
+///     .byte_limit(64 * 1024 * 1024)
+///     .max_entries(100_000)
+///     .low_watermark(0.75)
+///     .build();
+/// ```
+pub struct SharedLruBuilder {
+    byte_limit: usize,
+    max_entries: Option<usize>,
+    low_watermark: f32,
+    min_residency: Option<Duration>,
+    skip_busy_entries: bool,
+    count_entry_overhead: bool,
+    ghost_capacity: usize,
+    max_reasonable_entry_bytes: Option<usize>,
+    policy: Policy,
+    seed: Option<u64>,
+    construction_concurrency: Option<usize>,
+    recent_evictions: usize,
+}
+
+impl Default for SharedLruBuilder {
+    fn default() -> Self {
+        SharedLruBuilder {
+            byte_limit: usize::MAX,
+            max_entries: None,
+            low_watermark: 7. / 8.,
+            min_residency: None,
+            skip_busy_entries: false,
+            count_entry_overhead: false,
+            ghost_capacity: 0,
+            max_reasonable_entry_bytes: None,
+            policy: Policy::Lru,
+            seed: None,
+            construction_concurrency: None,
+            recent_evictions: 0,
+        }
+    }
+}
+
+impl SharedLruBuilder {
+    /// Total bytes shared by every cache on the pool. Unbounded unless set.
+    pub fn byte_limit(mut self, byte_limit: usize) -> Self {
+        self.byte_limit = byte_limit;
+        self
+    }
+
+    /// Same as [`SharedLruBuilder::byte_limit`], for pools whose caches charge some other cost.
+    /// See [`SharedLru::with_cost_limit`].
+    pub fn cost_limit(self, cost_limit: usize) -> Self {
+        self.byte_limit(cost_limit)
+    }
+
+    /// Maximum number of entries across every cache on the pool.
+    pub fn max_entries(mut self, max_entries: usize) -> Self {
+        self.max_entries = Some(max_entries);
+        self
+    }
+
+    /// Once the pool starts evicting, it keeps evicting until usage drops below this fraction of
+    /// the byte limit. Defaults to 7/8.
+    pub fn low_watermark(mut self, fraction: f32) -> Self {
+        self.low_watermark = fraction.clamp(0., 1.);
+        self
+    }
+
+    /// Spare entries inserted less than `min_residency` ago from eviction while older entries
+    /// remain, so bursts of inserts don't evict each other. Off by default.
+    pub fn min_residency(mut self, min_residency: Duration) -> Self {
+        self.min_residency = Some(min_residency);
+        self
+    }
+
+    /// When evicting, skip entries that a live `ValueRef` is blocking and evict the next oldest
+    /// instead of waiting for the reader.
+    ///
+    /// This keeps inserts responsive while guards are held, at the cost of slightly perturbing
+    /// strict LRU order under contention. Off by default.
+    pub fn skip_busy_entries(mut self, skip: bool) -> Self {
+        self.skip_busy_entries = skip;
+        self
+    }
+
+    /// Also charge each entry for the pool's own bookkeeping (ids, the cloned key and LRU links),
+    /// so the byte limit reflects total memory rather than just keys and values. Off by default.
+    pub fn count_entry_overhead(mut self, count: bool) -> Self {
+        self.count_entry_overhead = count;
+        self
+    }
+
+    /// Remember the hashes of this many recently evicted keys, counting misses on them in
+    /// [`SharedLru::ghost_hits`]. Off (0) by default.
+    ///
+    /// Each miss takes the pool lock while tracking is enabled.
+    pub fn ghost_capacity(mut self, capacity: usize) -> Self {
+        self.ghost_capacity = capacity;
+        self
+    }
+
+    /// Reject any entry measured above this many bytes, as if it could never fit.
+    ///
+    /// A guard against buggy `MemorySize` impls whose huge results would otherwise flush the pool.
+    /// Unlimited by default; debug builds always reject `usize::MAX`.
+    pub fn max_reasonable_entry_bytes(mut self, bytes: usize) -> Self {
+        self.max_reasonable_entry_bytes = Some(bytes);
+        self
+    }
+
+    /// How new entries are admitted once the pool is full. Defaults to [`Policy::Lru`].
+    pub fn policy(mut self, policy: Policy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Seed the pool's RNG, making entry ids and sampled touches reproducible. Seeded from
+    /// entropy by default.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Run at most `n` `get_or_insert` constructions at once across every cache on the pool;
+    /// further misses wait for a slot. Unlimited by default.
+    ///
+    /// Keeps a burst of misses from building many expensive values in parallel. A constructor
+    /// that itself calls `get_or_insert` on the same pool can deadlock once all slots are taken.
+    pub fn construction_concurrency(mut self, n: usize) -> Self {
+        self.construction_concurrency = Some(n);
+        self
+    }
+
+    /// Keep the last `n` evictions for [`SharedLru::recent_evictions`]. Off (0) by default, since
+    /// each recorded eviction copies the cache's name and reads the clock under the pool lock.
+    pub fn recent_evictions(mut self, n: usize) -> Self {
+        self.recent_evictions = n;
+        self
+    }
+
+    /// Like [`SharedLruBuilder::build`], but fails if the byte limit can't hold at least two
+    /// entries of `sample_entry_bytes`, catching e.g. a limit given in KB where MB was meant.
+    pub fn try_build(self, sample_entry_bytes: usize) -> Result<Arc<SharedLru>, LimitTooSmall> {
+        if self.byte_limit / sample_entry_bytes.max(1) < 2 {
+            return Err(LimitTooSmall {
+                byte_limit: self.byte_limit,
+                sample_entry_bytes,
+            });
+        }
+        Ok(self.build())
+    }
+
+    pub fn build(self) -> Arc<SharedLru> {
+        let mut allocator = match self.seed {
+            Some(seed) => Allocator::with_seed(self.byte_limit, seed),
+            None => Allocator::new(self.byte_limit),
+        };
+        allocator.set_max_entries(self.max_entries);
+        allocator.set_low_watermark(self.low_watermark);
+        allocator.set_min_residency(self.min_residency);
+        if let Policy::LfuWithAging { half_life } = self.policy {
+            allocator.set_aging_half_life(Some(half_life));
+        }
+
+        let mut inner = InnerShared::new(allocator);
+        inner.skip_busy_entries = self.skip_busy_entries;
+        inner.recent_evictions_capacity = self.recent_evictions;
+        if self.ghost_capacity > 0 {
+            inner.ghost = Some(GhostList::new(self.ghost_capacity));
+        }
+        let options = PoolOptions {
+            count_entry_overhead: self.count_entry_overhead,
+            track_ghosts: self.ghost_capacity > 0,
+            max_reasonable_entry_bytes: self.max_reasonable_entry_bytes,
+            policy: self.policy,
+            construction_concurrency: self.construction_concurrency,
+        };
+        SharedLru::from_inner(inner, options)
+    }
+}
+
+/// The byte limit given to [`SharedLruBuilder::try_build`] can't hold two sample entries.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LimitTooSmall {
+    pub byte_limit: usize,
+    pub sample_entry_bytes: usize,
+}
+
+impl fmt::Display for LimitTooSmall {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "byte limit of {} can't hold two entries of {} bytes",
+            self.byte_limit, self.sample_entry_bytes
+        )
+    }
+}
+
+impl std::error::Error for LimitTooSmall {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn try_build_rejects_tiny_limits() {
+        let err = SharedLru::builder().byte_limit(1024).try_build(1024 * 1024);
+        assert_eq!(
+            err.err(),
+            Some(LimitTooSmall {
+                byte_limit: 1024,
+                sample_entry_bytes: 1024 * 1024,
+            })
+        );
+
+        assert!(SharedLru::builder()
+            .byte_limit(2048)
+            .try_build(1024)
+            .is_ok());
+    }
+}
@@ -0,0 +1,128 @@
+use crate::{LruCache, MemorySize, SharedLru, Simple};
+use std::{borrow::Borrow, hash::Hash, sync::Arc};
+
+/// A compression format for [`CompressedCache`].
+///
+/// No codec is included, so the crate takes no compression dependency. Implement this over
+/// whichever compression crate suits the payloads, e.g. `lz4_flex` for speed or `zstd` for ratio.
+pub trait Codec: Simple {
+    fn compress(&self, data: &[u8]) -> Vec<u8>;
+
+    fn decompress(&self, data: &[u8]) -> Vec<u8>;
+}
+
+/// A cache of byte blobs stored compressed, so the pool is charged for the compressed size.
+///
+/// Every `insert` pays to compress and every `get` pays to decompress into a fresh buffer, in
+/// exchange for holding more entries in the same budget. Worth it for large, compressible
+/// payloads like JSON or text; not for small or already compressed ones.
+pub struct CompressedCache<K, C> {
+    cache: LruCache<K, Vec<u8>>,
+    codec: C,
+}
+
+impl SharedLru {
+    /// Like [`SharedLru::make_cache`], but values are stored compressed with `codec`.
+    pub fn make_compressed_cache<K, C>(self: &Arc<Self>, codec: C) -> CompressedCache<K, C>
+    where
+        K: Eq + Hash + Simple,
+        C: Codec,
+    {
+        CompressedCache {
+            cache: self.make_cache(),
+            codec,
+        }
+    }
+}
+
+impl<K, C> CompressedCache<K, C>
+where
+    K: MemorySize + Eq + Hash + Simple + Clone,
+    C: Codec,
+{
+    pub fn insert(&self, key: K, value: impl AsRef<[u8]>) {
+        let mut compressed = self.codec.compress(value.as_ref());
+        compressed.shrink_to_fit();
+        self.cache.insert(key, compressed);
+    }
+
+    /// Decompresses a copy of the value for `k`.
+    pub fn get<Q>(&self, k: &Q) -> Option<Vec<u8>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.cache
+            .get_project(k, |data| self.codec.decompress(data))
+    }
+
+    /// The compressed size of the value for `k`, as charged against the pool. Does not count as
+    /// a use.
+    pub fn compressed_bytes<Q>(&self, k: &Q) -> Option<usize>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.cache.charged_bytes(k)
+    }
+
+    pub fn pop<Q>(&self, k: &Q) -> Option<Vec<u8>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.cache.pop(k).map(|data| self.codec.decompress(&data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Run-length encoding as (count, byte) pairs.
+    struct Rle;
+
+    impl Codec for Rle {
+        fn compress(&self, data: &[u8]) -> Vec<u8> {
+            let mut out = Vec::new();
+            for &byte in data {
+                match out.len() {
+                    len if len >= 2 && out[len - 1] == byte && out[len - 2] < u8::MAX => {
+                        out[len - 2] += 1
+                    }
+                    _ => out.extend([1, byte]),
+                }
+            }
+            out
+        }
+
+        fn decompress(&self, data: &[u8]) -> Vec<u8> {
+            data.chunks(2)
+                .flat_map(|pair| std::iter::repeat(pair[1]).take(pair[0] as usize))
+                .collect()
+        }
+    }
+
+    #[test]
+    fn charges_compressed_size() {
+        let shared = SharedLru::with_byte_limit(10_000);
+        let cache = shared.make_compressed_cache(Rle);
+        cache.insert(0u8, vec![7u8; 1000]);
+
+        assert_eq!(cache.get(&0), Some(vec![7u8; 1000]));
+        assert!(cache.compressed_bytes(&0).unwrap() < 100);
+    }
+
+    #[test]
+    fn size_queries_do_not_promote() {
+        let shared = SharedLru::with_byte_limit(2 * (1 + std::mem::size_of::<Vec<u8>>() + 2));
+        let cache = shared.make_compressed_cache(Rle);
+        cache.insert(0u8, [7u8]);
+        cache.insert(1u8, [7u8]);
+
+        assert!(cache.compressed_bytes(&0).is_some());
+        cache.insert(2u8, [7u8]);
+        assert_eq!(cache.get(&0), None);
+        assert_eq!(cache.get(&1), Some(vec![7]));
+    }
+}
@@ -0,0 +1,78 @@
+use std::sync::{Condvar, Mutex};
+
+/// Caps how many values `get_or_insert` and friends build at once, across every cache on a pool.
+pub(crate) struct ConstructionLimit {
+    max: usize,
+    running: Mutex<usize>,
+    finished: Condvar,
+}
+
+impl ConstructionLimit {
+    pub(crate) fn new(max: usize) -> Self {
+        ConstructionLimit {
+            max: max.max(1),
+            running: Mutex::new(0),
+            finished: Condvar::new(),
+        }
+    }
+
+    /// Runs `f` once fewer than `max` other constructions are running.
+    pub(crate) fn run<R>(&self, f: impl FnOnce() -> R) -> R {
+        let _permit = self.acquire();
+        f()
+    }
+
+    fn acquire(&self) -> Permit<'_> {
+        let mut running = self.running.lock().unwrap();
+        while *running >= self.max {
+            running = self.finished.wait(running).unwrap();
+        }
+        *running += 1;
+        Permit(self)
+    }
+}
+
+/// Releases its slot when dropped, even if construction panics.
+struct Permit<'l>(&'l ConstructionLimit);
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        *self.0.running.lock().unwrap() -= 1;
+        self.0.finished.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    #[test]
+    fn never_exceeds_max() {
+        let limit = Arc::new(ConstructionLimit::new(2));
+        let running = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+
+        let threads = (0..8)
+            .map(|_| {
+                let (limit, running, peak) = (limit.clone(), running.clone(), peak.clone());
+                std::thread::spawn(move || {
+                    limit.run(|| {
+                        let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                        peak.fetch_max(now, Ordering::SeqCst);
+                        std::thread::sleep(std::time::Duration::from_millis(5));
+                        running.fetch_sub(1, Ordering::SeqCst);
+                    })
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(peak.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,74 @@
+use std::collections::{HashMap, VecDeque};
+
+/// Hashes of recently evicted keys, used to spot misses a larger pool would have served.
+pub(crate) struct GhostList {
+    capacity: usize,
+    order: VecDeque<u64>,
+    counts: HashMap<u64, usize>,
+    hits: u64,
+}
+
+impl GhostList {
+    pub(crate) fn new(capacity: usize) -> Self {
+        GhostList {
+            capacity,
+            order: VecDeque::with_capacity(capacity),
+            counts: HashMap::with_capacity(capacity),
+            hits: 0,
+        }
+    }
+
+    pub(crate) fn record(&mut self, key_hash: u64) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        if self.order.len() == self.capacity {
+            let oldest = self.order.pop_front().expect("capacity is not 0");
+            if let Some(count) = self.counts.get_mut(&oldest) {
+                *count -= 1;
+                if *count == 0 {
+                    self.counts.remove(&oldest);
+                }
+            }
+        }
+        self.order.push_back(key_hash);
+        *self.counts.entry(key_hash).or_insert(0) += 1;
+    }
+
+    pub(crate) fn contains(&self, key_hash: u64) -> bool {
+        self.counts.contains_key(&key_hash)
+    }
+
+    /// Returns whether the missed key was evicted recently. Each eviction is counted as a hit at
+    /// most once.
+    pub(crate) fn record_miss(&mut self, key_hash: u64) -> bool {
+        let hit = self.contains(key_hash);
+        if hit {
+            self.hits += 1;
+            self.remove(key_hash);
+        }
+        hit
+    }
+
+    /// Forgets that the key was evicted, e.g. because it is cached again.
+    pub(crate) fn remove(&mut self, key_hash: u64) {
+        if self.counts.remove(&key_hash).is_some() {
+            self.order.retain(|&hash| hash != key_hash);
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.order.clear();
+        self.counts.clear();
+        self.hits = 0;
+    }
+
+    pub(crate) fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub(crate) fn hits(&self) -> u64 {
+        self.hits
+    }
+}
@@ -1,37 +1,238 @@
-use std::mem::size_of;
+use alloc::{
+    boxed::Box,
+    collections::{BinaryHeap, LinkedList, VecDeque},
+    rc::Rc,
+    string::String,
+    sync::Arc,
+    vec::Vec,
+};
+use core::{
+    cell::{Cell, RefCell},
+    mem::size_of,
+};
 
+/// The bytes a value occupies, on the stack and everything it owns on the heap.
+///
+/// Collections count their reserved but unused capacity too, since that memory is allocated
+/// whether or not it holds anything.
 pub trait MemorySize {
     fn bytes(&self) -> usize;
+
+    /// Total bytes of a contiguous run of values, as stored in a `Vec`.
+    ///
+    /// [`JustStack`] types override this to avoid visiting every element.
+    fn slice_bytes(items: &[Self]) -> usize
+    where
+        Self: Sized,
+    {
+        items.iter().map(MemorySize::bytes).sum()
+    }
+
+    /// Like [`MemorySize::bytes`], but collections longer than `threshold` measure `sample_size`
+    /// evenly spaced elements and extrapolate, bounding the cost for huge values.
+    ///
+    /// Only the outermost collection is sampled; each sampled element is measured in full.
+    fn bytes_sampled(&self, threshold: usize, sample_size: usize) -> usize {
+        let _ = (threshold, sample_size);
+        self.bytes()
+    }
+}
+
+/// Extrapolates the total size of `len` items from `sample_size` of them spread evenly through
+/// `items`. Steps over `items` before measuring, so only the sampled items are measured.
+fn extrapolate<I>(
+    len: usize,
+    sample_size: usize,
+    items: impl Iterator<Item = I>,
+    measure: impl Fn(I) -> usize,
+) -> usize {
+    let sample_size = sample_size.clamp(1, len.max(1));
+    let sampled = items
+        .step_by(len / sample_size)
+        .take(sample_size)
+        .map(measure)
+        .sum::<usize>();
+    sampled.saturating_mul(len) / sample_size
 }
 
-// TODO(shelbyd): Specialize for T: JustStack.
 impl<T> MemorySize for Vec<T>
 where
     T: MemorySize,
 {
     fn bytes(&self) -> usize {
+        size_of::<Self>() + T::slice_bytes(self) + (self.capacity() - self.len()) * size_of::<T>()
+    }
+
+    fn bytes_sampled(&self, threshold: usize, sample_size: usize) -> usize {
+        if self.len() <= threshold {
+            return self.bytes();
+        }
+        size_of::<Self>()
+            + extrapolate(self.len(), sample_size, self.iter(), T::bytes)
+            + (self.capacity() - self.len()) * size_of::<T>()
+    }
+}
+
+/// The ring buffer is a single allocation of exactly `capacity` slots, so there is no overhead
+/// beyond the spare capacity.
+impl<T: MemorySize> MemorySize for VecDeque<T> {
+    fn bytes(&self) -> usize {
+        let (front, back) = self.as_slices();
+        size_of::<Self>()
+            + T::slice_bytes(front)
+            + T::slice_bytes(back)
+            + (self.capacity() - self.len()) * size_of::<T>()
+    }
+
+    fn bytes_sampled(&self, threshold: usize, sample_size: usize) -> usize {
+        if self.len() <= threshold {
+            return self.bytes();
+        }
         size_of::<Self>()
-            + self.iter().map(|v| v.bytes()).sum::<usize>()
+            + extrapolate(self.len(), sample_size, self.iter(), T::bytes)
             + (self.capacity() - self.len()) * size_of::<T>()
     }
 }
 
-impl<T: MemorySize> MemorySize for std::collections::VecDeque<T> {
+impl<T: MemorySize> MemorySize for BinaryHeap<T> {
     fn bytes(&self) -> usize {
+        // `as_slice` would allow `slice_bytes`, but needs Rust 1.80.
         size_of::<Self>()
-            + self.iter().map(|v| v.bytes()).sum::<usize>()
+            + self.iter().map(T::bytes).sum::<usize>()
             + (self.capacity() - self.len()) * size_of::<T>()
     }
 }
 
+impl<T: MemorySize> MemorySize for LinkedList<T> {
+    fn bytes(&self) -> usize {
+        // Each node is allocated separately with next and prev pointers.
+        let links = 2 * size_of::<usize>();
+        size_of::<Self>() + self.iter().map(|item| links + item.bytes()).sum::<usize>()
+    }
+}
+
+/// Control bytes past the last bucket of a hashbrown table, one SIMD group wide. Groups are 16
+/// bytes with SSE2 and 8 otherwise; the larger is assumed.
+#[cfg(feature = "std")]
+const HASH_GROUP_WIDTH: usize = 16;
+
+/// Models the hashbrown table behind `HashMap`: a power-of-two number of buckets, each a `(K, V)`
+/// slot plus one control byte, and a trailing group of control bytes. `capacity` is 7/8 of the
+/// buckets, or one less than them below 8, so the bucket count is recovered from it. Alignment
+/// padding between the slots and control bytes is not counted.
+#[cfg(feature = "std")]
+fn hash_table_bytes<K, V>(capacity: usize) -> usize {
+    let buckets = match capacity {
+        0 => return 0,
+        1..=7 => capacity + 1,
+        _ => (capacity / 7).saturating_mul(8),
+    };
+    buckets
+        .saturating_mul(size_of::<(K, V)>() + 1)
+        .saturating_add(HASH_GROUP_WIDTH)
+}
+
+/// What a key and value own beyond their slot in the table.
+#[cfg(feature = "std")]
+fn entry_heap_bytes<K: MemorySize, V: MemorySize>((k, v): (&K, &V)) -> usize {
+    k.bytes().saturating_sub(size_of::<K>()) + v.bytes().saturating_sub(size_of::<V>())
+}
+
+#[cfg(feature = "std")]
 impl<K: MemorySize, V: MemorySize> MemorySize for std::collections::HashMap<K, V> {
     fn bytes(&self) -> usize {
         size_of::<Self>()
-            + self
-                .iter()
-                .map(|(k, v)| k.bytes() + v.bytes())
-                .sum::<usize>()
-            + (self.capacity() - self.len()) * size_of::<(K, V)>()
+            + hash_table_bytes::<K, V>(self.capacity())
+            + self.iter().map(entry_heap_bytes).sum::<usize>()
+    }
+
+    fn bytes_sampled(&self, threshold: usize, sample_size: usize) -> usize {
+        if self.len() <= threshold {
+            return self.bytes();
+        }
+        size_of::<Self>()
+            + hash_table_bytes::<K, V>(self.capacity())
+            + extrapolate(self.len(), sample_size, self.iter(), entry_heap_bytes)
+    }
+}
+
+// A blanket impl for `Box<T>` would overlap with the `JustStack` one, since `Box` is
+// fundamental, so only boxed `MemorySize` trait objects are covered. For `Box<dyn MyTrait>`, make
+// `MemorySize` a supertrait of `MyTrait` and implement it for the box the same way.
+macro_rules! boxed_dyn_memory_size {
+    ($($bounds:tt)*) => {
+        impl MemorySize for Box<dyn MemorySize $($bounds)*> {
+            fn bytes(&self) -> usize {
+                size_of::<Self>() + (**self).bytes()
+            }
+        }
+    };
+}
+
+boxed_dyn_memory_size!();
+boxed_dyn_memory_size!(+ Send);
+boxed_dyn_memory_size!(+ Send + Sync);
+
+impl<T: MemorySize + ?Sized> MemorySize for Arc<T> {
+    fn bytes(&self) -> usize {
+        // Strong and weak counts live alongside the value.
+        size_of::<Self>() + 2 * size_of::<usize>() + T::bytes(self)
+    }
+}
+
+impl<T: MemorySize + ?Sized> MemorySize for Rc<T> {
+    fn bytes(&self) -> usize {
+        size_of::<Self>() + 2 * size_of::<usize>() + T::bytes(self)
+    }
+}
+
+/// Measures the value if it can be borrowed. While it is mutably borrowed elsewhere, only its
+/// stack size is counted.
+impl<T: MemorySize> MemorySize for RefCell<T> {
+    fn bytes(&self) -> usize {
+        let inner = match self.try_borrow() {
+            Ok(value) => value.bytes(),
+            Err(_) => size_of::<T>(),
+        };
+        size_of::<Self>() - size_of::<T>() + inner
+    }
+}
+
+impl<T: MemorySize + Copy> MemorySize for Cell<T> {
+    fn bytes(&self) -> usize {
+        self.get().bytes()
+    }
+}
+
+/// Measures the guarded value if the lock is free. If it is held elsewhere, only the lock's own
+/// stack size is counted rather than blocking, which could deadlock if the holder is waiting on
+/// the cache.
+#[cfg(feature = "std")]
+impl<T: MemorySize> MemorySize for std::sync::Mutex<T> {
+    fn bytes(&self) -> usize {
+        use std::sync::TryLockError;
+
+        let inner = match self.try_lock() {
+            Ok(guard) => guard.bytes(),
+            Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner().bytes(),
+            Err(TryLockError::WouldBlock) => size_of::<T>(),
+        };
+        size_of::<Self>() - size_of::<T>() + inner
+    }
+}
+
+/// Like the `Mutex` impl, falls back to the stack size if the lock is write-locked elsewhere.
+#[cfg(feature = "std")]
+impl<T: MemorySize> MemorySize for std::sync::RwLock<T> {
+    fn bytes(&self) -> usize {
+        use std::sync::TryLockError;
+
+        let inner = match self.try_read() {
+            Ok(guard) => guard.bytes(),
+            Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner().bytes(),
+            Err(TryLockError::WouldBlock) => size_of::<T>(),
+        };
+        size_of::<Self>() - size_of::<T>() + inner
     }
 }
 
@@ -43,7 +244,7 @@ impl MemorySize for str {
 
 impl MemorySize for String {
     fn bytes(&self) -> usize {
-        size_of::<Self>() + self.len()
+        size_of::<Self>() + self.capacity()
     }
 }
 
@@ -54,6 +255,10 @@ where
     fn bytes(&self) -> usize {
         size_of::<Self>()
     }
+
+    fn slice_bytes(items: &[Self]) -> usize {
+        core::mem::size_of_val(items)
+    }
 }
 
 impl<T> MemorySize for Option<T>
@@ -69,28 +274,90 @@ where
     }
 }
 
+/// Measured without recursion, so deeply nested untrusted input can't overflow the stack.
 #[cfg(feature = "serde_json")]
 impl MemorySize for serde_json::Value {
     fn bytes(&self) -> usize {
+        self.bytes_with_depth_limit(usize::MAX)
+    }
+}
+
+#[cfg(feature = "serde_json")]
+impl DepthLimitedSize for serde_json::Value {
+    fn bytes_with_depth_limit(&self, max_depth: usize) -> usize {
         use serde_json::Value::*;
 
-        let bonus = match self {
-            Null | Bool(_) | Number(_) => 0,
-            String(s) => s.len(),
-            Array(arr) => arr.iter().map(MemorySize::bytes).sum(),
-            Object(map) => map
-                .iter()
-                .map(|(key, value)| MemorySize::bytes(key) + MemorySize::bytes(value))
-                .sum(),
-        };
+        let mut total = 0;
+        let mut pending = alloc::vec![(self, 0)];
+        while let Some((value, depth)) = pending.pop() {
+            total += size_of::<Self>();
+            if depth == max_depth {
+                continue;
+            }
+            match value {
+                Null | Bool(_) | Number(_) => {}
+                String(s) => total += s.capacity(),
+                Array(arr) => {
+                    total += (arr.capacity() - arr.len()) * size_of::<Self>();
+                    pending.extend(arr.iter().map(|item| (item, depth + 1)));
+                }
+                Object(map) => {
+                    for (key, item) in map {
+                        total += MemorySize::bytes(key);
+                        pending.push((item, depth + 1));
+                    }
+                }
+            }
+        }
+        total
+    }
+}
+
+/// Measures a nested structure without descending more than `max_depth` levels. Anything deeper
+/// is counted as its stack size only.
+///
+/// For structures that may be deeply nested or cyclic, such as graphs of `Arc`s, where a plain
+/// recursive [`MemorySize`] would overflow the stack or never finish. Wrap values in
+/// [`DepthLimited`] to cache them with this measure.
+pub trait DepthLimitedSize {
+    fn bytes_with_depth_limit(&self, max_depth: usize) -> usize;
+}
+
+/// A value measured with [`DepthLimitedSize`] down to `max_depth`.
+pub struct DepthLimited<T> {
+    pub value: T,
+    pub max_depth: usize,
+}
+
+impl<T: DepthLimitedSize> MemorySize for DepthLimited<T> {
+    fn bytes(&self) -> usize {
+        size_of::<Self>() - size_of::<T>() + self.value.bytes_with_depth_limit(self.max_depth)
+    }
+}
+
+/// A type that tracks its own heap footprint, so it can be measured in O(1) rather than by
+/// visiting what it owns. Wrap values in [`SelfSizedValue`] to cache them with this measure.
+pub trait SelfSized {
+    /// Bytes allocated on the heap, including reserved but unused capacity.
+    fn heap_bytes(&self) -> usize;
+}
 
-        size_of::<Self>() + bonus
+/// A value measured with [`SelfSized::heap_bytes`] instead of a recursive [`MemorySize`].
+pub struct SelfSizedValue<T>(pub T);
+
+impl<T: SelfSized> MemorySize for SelfSizedValue<T> {
+    fn bytes(&self) -> usize {
+        size_of::<T>() + self.0.heap_bytes()
     }
 }
 
 pub trait JustStack {}
 
 impl JustStack for bool {}
+impl JustStack for char {}
+
+impl JustStack for f32 {}
+impl JustStack for f64 {}
 
 impl JustStack for u8 {}
 impl JustStack for u16 {}
@@ -104,9 +371,249 @@ impl JustStack for i32 {}
 impl JustStack for i64 {}
 impl JustStack for isize {}
 
+impl JustStack for core::num::NonZeroU8 {}
+impl JustStack for core::num::NonZeroU16 {}
+impl JustStack for core::num::NonZeroU32 {}
+impl JustStack for core::num::NonZeroU64 {}
+impl JustStack for core::num::NonZeroUsize {}
+
+impl JustStack for core::num::NonZeroI8 {}
+impl JustStack for core::num::NonZeroI16 {}
+impl JustStack for core::num::NonZeroI32 {}
+impl JustStack for core::num::NonZeroI64 {}
+impl JustStack for core::num::NonZeroIsize {}
+
+impl JustStack for core::time::Duration {}
+#[cfg(feature = "std")]
+impl JustStack for std::time::Instant {}
+#[cfg(feature = "std")]
+impl JustStack for std::time::SystemTime {}
+
+#[cfg(feature = "std")]
+impl JustStack for std::net::Ipv4Addr {}
+#[cfg(feature = "std")]
+impl JustStack for std::net::Ipv6Addr {}
+#[cfg(feature = "std")]
+impl JustStack for std::net::IpAddr {}
+#[cfg(feature = "std")]
+impl JustStack for std::net::SocketAddrV4 {}
+#[cfg(feature = "std")]
+impl JustStack for std::net::SocketAddrV6 {}
+#[cfg(feature = "std")]
+impl JustStack for std::net::SocketAddr {}
+
 impl<T: ?Sized> JustStack for &T {}
 impl<T: ?Sized> JustStack for &mut T {}
 
-impl<A: JustStack> JustStack for (A,) {}
-impl<A: JustStack, B: JustStack> JustStack for (A, B) {}
-impl<A: JustStack, B: JustStack, C: JustStack> JustStack for (A, B, C) {}
+impl<T: JustStack, const N: usize> JustStack for [T; N] {}
+
+/// Tuples count their own stack size, padding included, plus whatever each element owns.
+macro_rules! tuple_memory_size {
+    ($($name:ident),+) => {
+        impl<$($name: MemorySize),+> MemorySize for ($($name,)+) {
+            #[allow(non_snake_case)]
+            fn bytes(&self) -> usize {
+                let ($($name,)+) = self;
+                size_of::<Self>() $(+ $name.bytes().saturating_sub(size_of::<$name>()))+
+            }
+        }
+    };
+}
+
+tuple_memory_size!(A);
+tuple_memory_size!(A, B);
+tuple_memory_size!(A, B, C);
+tuple_memory_size!(A, B, C, D);
+tuple_memory_size!(A, B, C, D, E);
+tuple_memory_size!(A, B, C, D, E, F);
+tuple_memory_size!(A, B, C, D, E, F, G);
+tuple_memory_size!(A, B, C, D, E, F, G, H);
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn large_byte_vec() {
+        let vec = vec![0u8; 10 * 1024 * 1024];
+        assert_eq!(vec.bytes(), size_of::<Vec<u8>>() + 10 * 1024 * 1024);
+    }
+
+    #[test]
+    fn collections_count_spare_capacity() {
+        let mut vec = Vec::<u64>::with_capacity(1000);
+        vec.push(0);
+        assert_eq!(vec.bytes(), size_of::<Vec<u64>>() + 1000 * 8);
+
+        let mut string = String::with_capacity(1000);
+        string.push('a');
+        assert_eq!(string.bytes(), size_of::<String>() + 1000);
+
+        let mut deque = std::collections::VecDeque::<u64>::with_capacity(1000);
+        deque.push_back(0);
+        assert_eq!(
+            deque.bytes(),
+            size_of::<std::collections::VecDeque<u64>>() + deque.capacity() * 8
+        );
+    }
+
+    #[test]
+    fn hash_maps_count_buckets_and_control_bytes() {
+        use std::collections::HashMap;
+
+        assert_eq!(
+            HashMap::<u64, u64>::new().bytes(),
+            size_of::<HashMap<u64, u64>>()
+        );
+
+        // 1000 entries at 7/8 load need 2048 buckets.
+        let mut map = HashMap::<u64, String>::with_capacity(1000);
+        map.insert(0, String::from("abcd"));
+        assert_eq!(map.capacity(), 1792);
+        assert_eq!(
+            map.bytes(),
+            size_of::<HashMap<u64, String>>() + 2048 * (size_of::<(u64, String)>() + 1) + 16 + 4
+        );
+
+        // Tables of small entries start at 8 buckets.
+        let mut small = HashMap::<u8, u8>::new();
+        small.insert(0, 0);
+        assert_eq!(small.capacity(), 7);
+        assert_eq!(
+            small.bytes(),
+            size_of::<HashMap<u8, u8>>() + 8 * (2 + 1) + 16
+        );
+    }
+
+    #[test]
+    fn sampling_extrapolates_long_collections() {
+        let vec = (0..1000).map(|_| String::from("abcd")).collect::<Vec<_>>();
+        assert_eq!(vec.bytes_sampled(1000, 10), vec.bytes());
+        assert_eq!(vec.bytes_sampled(100, 10), vec.bytes());
+
+        let uneven = (0..1000).map(|i| "a".repeat(i % 2)).collect::<Vec<_>>();
+        let sampled = uneven.bytes_sampled(100, 100) as f64;
+        assert!((sampled / uneven.bytes() as f64 - 1.).abs() < 0.05);
+    }
+
+    #[test]
+    fn sampling_only_measures_the_sample() {
+        use std::{
+            cell::Cell,
+            collections::{HashMap, VecDeque},
+        };
+
+        struct Counted<'c>(&'c Cell<usize>);
+
+        impl MemorySize for Counted<'_> {
+            fn bytes(&self) -> usize {
+                self.0.set(self.0.get() + 1);
+                size_of::<Self>()
+            }
+        }
+
+        let calls = Cell::new(0);
+        let vec = (0..100_000).map(|_| Counted(&calls)).collect::<Vec<_>>();
+        vec.bytes_sampled(1000, 10);
+        assert_eq!(calls.replace(0), 10);
+
+        let deque = vec.into_iter().collect::<VecDeque<_>>();
+        deque.bytes_sampled(1000, 10);
+        assert_eq!(calls.replace(0), 10);
+
+        let map = (0..100_000u32)
+            .map(|i| (i, Counted(&calls)))
+            .collect::<HashMap<_, _>>();
+        map.bytes_sampled(1000, 10);
+        assert_eq!(calls.replace(0), 10);
+    }
+
+    #[test]
+    fn vec_of_heap_values_visits_elements() {
+        let vec = vec![String::from("abc"), String::from("defgh")];
+        assert_eq!(
+            vec.bytes(),
+            size_of::<Vec<String>>() + 2 * size_of::<String>() + 8
+        );
+    }
+
+    #[test]
+    fn tuples_count_heap_of_each_element() {
+        let pair = (String::from("abc"), vec![0u8; 10]);
+        assert_eq!(pair.bytes(), size_of::<(String, Vec<u8>)>() + 3 + 10);
+
+        let mixed = (1u8, 2u64, String::from("abcd"));
+        assert_eq!(mixed.bytes(), size_of::<(u8, u64, String)>() + 4);
+    }
+
+    #[test]
+    fn linked_list_counts_links() {
+        let list = (0..3u64).collect::<std::collections::LinkedList<_>>();
+        assert_eq!(
+            list.bytes(),
+            size_of::<std::collections::LinkedList<u64>>() + 3 * (2 * size_of::<usize>() + 8)
+        );
+    }
+
+    #[test]
+    fn boxed_trait_objects_measure_contents() {
+        let boxed: Box<dyn MemorySize + Send + Sync> = Box::new(vec![0u8; 100]);
+        assert_eq!(
+            boxed.bytes(),
+            size_of::<Box<dyn MemorySize>>() + size_of::<Vec<u8>>() + 100
+        );
+    }
+
+    #[test]
+    fn borrowed_ref_cell_counts_only_stack() {
+        let cell = std::cell::RefCell::new(vec![0u8; 100]);
+        assert_eq!(cell.bytes(), size_of::<std::cell::RefCell<Vec<u8>>>() + 100);
+
+        let _borrow = cell.borrow_mut();
+        assert_eq!(cell.bytes(), size_of::<std::cell::RefCell<Vec<u8>>>());
+    }
+
+    #[cfg(feature = "serde_json")]
+    #[test]
+    fn deeply_nested_json_does_not_overflow() {
+        let mut value = serde_json::Value::Null;
+        for _ in 0..100_000 {
+            value = serde_json::Value::Array(vec![value]);
+        }
+        assert_eq!(value.bytes(), 100_001 * size_of::<serde_json::Value>());
+        assert_eq!(
+            value.bytes_with_depth_limit(2),
+            3 * size_of::<serde_json::Value>()
+        );
+
+        // Dropping is recursive too, so unwind the nesting by hand.
+        while let serde_json::Value::Array(mut items) = value {
+            value = items.pop().unwrap();
+        }
+    }
+
+    #[test]
+    fn self_sized_values_report_their_heap() {
+        struct Buffer {
+            allocated: usize,
+        }
+
+        impl SelfSized for Buffer {
+            fn heap_bytes(&self) -> usize {
+                self.allocated
+            }
+        }
+
+        let value = SelfSizedValue(Buffer { allocated: 4096 });
+        assert_eq!(value.bytes(), size_of::<Buffer>() + 4096);
+    }
+
+    #[test]
+    fn locked_mutex_counts_only_stack() {
+        let mutex = std::sync::Mutex::new(vec![0u8; 100]);
+        assert_eq!(mutex.bytes(), size_of::<std::sync::Mutex<Vec<u8>>>() + 100);
+
+        let _guard = mutex.lock().unwrap();
+        assert_eq!(mutex.bytes(), size_of::<std::sync::Mutex<Vec<u8>>>());
+    }
+}
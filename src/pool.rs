@@ -0,0 +1,3688 @@
+use crate::{
+    allocator::{AllocResult, Allocator, IdBuildHasher},
+    construction::ConstructionLimit,
+    ghost::GhostList,
+    sampler::TouchSampler,
+    sketch::FrequencySketch,
+    EntryId, EntrySnapshot, MemorySize, PoolSnapshot, SharedLruBuilder, SizeBucket,
+};
+use dashmap::{
+    mapref::{entry::Entry, one::Ref},
+    DashMap,
+};
+use std::{
+    borrow::Borrow,
+    collections::{hash_map::DefaultHasher, HashMap, HashSet, VecDeque},
+    fmt::Debug,
+    hash::{BuildHasher, Hash, Hasher},
+    mem::size_of,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        Arc, Condvar, Mutex, Weak,
+    },
+    time::{Duration, Instant},
+};
+
+/// How many eviction events each [`SharedLru::eviction_events`] receiver may fall behind by.
+const EVENT_BUFFER: usize = 1024;
+
+pub struct SharedLru {
+    inner: Mutex<InnerShared>,
+    eviction_started: Condvar,
+    options: PoolOptions,
+    sketch: Option<FrequencySketch>,
+    construction: Option<ConstructionLimit>,
+    /// Shared with `InnerShared`, so inserts can check for evictions without the lock.
+    evictions: Arc<AtomicU64>,
+}
+
+/// An id claimed for a new entry, and how many evictions had started when it was.
+#[derive(Clone, Copy)]
+struct Claimed {
+    id: EntryId,
+    evictions: u64,
+    /// Whether claiming it evicted anything.
+    evicted: bool,
+    /// Bytes left free in the pool just after claiming it.
+    headroom: usize,
+}
+
+/// Settings fixed when the pool is built.
+pub(crate) struct PoolOptions {
+    pub(crate) count_entry_overhead: bool,
+    pub(crate) track_ghosts: bool,
+    pub(crate) max_reasonable_entry_bytes: Option<usize>,
+    pub(crate) policy: Policy,
+    pub(crate) construction_concurrency: Option<usize>,
+}
+
+/// How a pool decides whether a new entry is worth evicting others for, and which to evict.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    /// Always admit, evicting the least recently used entries. The default.
+    Lru,
+    /// Admit a new entry only if it has been accessed more often recently than the entry it
+    /// would displace, estimated with a count-min sketch. Protects popular entries from scans of
+    /// keys that are only used once.
+    TinyLfu,
+    /// Always admit, evicting the entries accessed least often, where each access counts half
+    /// as much after every `half_life`. Suits steady popular keys mixed with bursts.
+    LfuWithAging { half_life: Duration },
+}
+
+/// Counters per sketch row; four rows of 16K one-byte counters.
+const SKETCH_WIDTH: usize = 1 << 14;
+
+impl SharedLru {
+    /// Shortcut for `SharedLru::builder().byte_limit(byte_limit).build()`.
+    pub fn with_byte_limit(byte_limit: usize) -> Arc<SharedLru> {
+        SharedLru::builder().byte_limit(byte_limit).build()
+    }
+
+    /// Like [`SharedLru::with_byte_limit`], for pools limited by something other than memory.
+    ///
+    /// The pool only ever adds up what entries are charged, so "bytes" can be any additive cost,
+    /// such as file descriptors or GPU memory. Give each cache a [`Measure::Custom`] that reports
+    /// the cost of an entry.
+    pub fn with_cost_limit(cost_limit: usize) -> Arc<SharedLru> {
+        SharedLru::with_byte_limit(cost_limit)
+    }
+
+    /// Like [`SharedLru::with_byte_limit`], but with a fixed seed so ids and sampling are
+    /// reproducible, for tests and benchmarks.
+    pub fn with_byte_limit_seeded(byte_limit: usize, seed: u64) -> Arc<SharedLru> {
+        SharedLru::builder()
+            .byte_limit(byte_limit)
+            .seed(seed)
+            .build()
+    }
+
+    pub fn builder() -> SharedLruBuilder {
+        SharedLruBuilder::default()
+    }
+
+    pub(crate) fn from_inner(inner: InnerShared, options: PoolOptions) -> Arc<SharedLru> {
+        let sketch = match options.policy {
+            Policy::Lru | Policy::LfuWithAging { .. } => None,
+            Policy::TinyLfu => Some(FrequencySketch::new(SKETCH_WIDTH)),
+        };
+        Arc::new(SharedLru {
+            evictions: Arc::clone(&inner.evictions),
+            inner: Mutex::new(inner),
+            eviction_started: Condvar::new(),
+            construction: options.construction_concurrency.map(ConstructionLimit::new),
+            options,
+            sketch,
+        })
+    }
+
+    pub fn make_cache<K, V>(self: &Arc<Self>) -> LruCache<K, V>
+    where
+        K: Eq + Hash + Simple,
+        V: Simple,
+    {
+        self.make_cache_inner(None, EntryMap::default())
+    }
+
+    /// Like [`SharedLru::make_cache`], with a name for debugging that
+    /// [`SharedLru::owner_of`] reports for the cache's entries.
+    pub fn make_named_cache<K, V>(self: &Arc<Self>, name: impl Into<String>) -> LruCache<K, V>
+    where
+        K: Eq + Hash + Simple,
+        V: Simple,
+    {
+        let entry_map = EntryMap {
+            name: Some(name.into()),
+            ..EntryMap::default()
+        };
+        self.make_cache_inner(None, entry_map)
+    }
+
+    /// Like [`SharedLru::make_cache`], but the cache may hold at most `max_bytes`, even when the
+    /// pool has room. Once full, inserts evict the cache's own oldest entries first.
+    ///
+    /// The pool's byte limit still applies on top: a cache under its quota can still lose
+    /// entries when other caches need room.
+    pub fn make_cache_with_quota<K, V>(self: &Arc<Self>, max_bytes: usize) -> LruCache<K, V>
+    where
+        K: Eq + Hash + Simple,
+        V: Simple,
+    {
+        self.make_cache_inner(Some(max_bytes), EntryMap::default())
+    }
+
+    /// Like [`SharedLru::make_cache`], for values behind an `Arc` so hot read paths can use
+    /// [`LruCache::get_arc`] and hold no lock once it returns.
+    ///
+    /// Each entry is charged for the `Arc` and the value, even if the value is also shared
+    /// elsewhere.
+    pub fn make_shared_value_cache<K, V>(self: &Arc<Self>) -> LruCache<K, Arc<V>>
+    where
+        K: Eq + Hash + Simple,
+        V: Simple,
+    {
+        self.make_cache()
+    }
+
+    /// Like [`SharedLru::make_cache`], but every evicted entry is handed to `on_evict` instead of
+    /// being dropped.
+    pub(crate) fn make_cache_with_hook<K, V>(
+        self: &Arc<Self>,
+        on_evict: EvictHook<K, V>,
+    ) -> LruCache<K, V>
+    where
+        K: Eq + Hash + Simple,
+        V: Simple,
+    {
+        let entry_map = EntryMap {
+            on_evict: Some(on_evict),
+            ..EntryMap::default()
+        };
+        self.make_cache_inner(None, entry_map)
+    }
+
+    fn make_cache_inner<K, V>(
+        self: &Arc<Self>,
+        quota: Option<usize>,
+        entry_map: EntryMap<K, V>,
+    ) -> LruCache<K, V>
+    where
+        K: Eq + Hash + Simple,
+        V: Simple,
+    {
+        let entry_map = Arc::new(entry_map);
+        let as_trait: Weak<dyn EntryHolder> =
+            Arc::downgrade(&(Arc::clone(&entry_map) as Arc<dyn EntryHolder>));
+        let seed = {
+            let mut inner = self.inner.lock().unwrap();
+            if let Some(max_bytes) = quota {
+                inner
+                    .quotas
+                    .insert(holder_key(&as_trait), Quota { max_bytes, used: 0 });
+            }
+            inner.register(as_trait);
+            inner.allocator.next_seed()
+        };
+
+        LruCache {
+            shared: Arc::clone(self),
+            entry_map,
+            large_value_warning: None,
+            touch_sampler: TouchSampler::new(seed),
+            frozen: AtomicBool::new(false),
+            measure: Measure::Deep,
+        }
+    }
+
+    /// Runs `f` under the inner lock, dropping anything it evicted only after the lock is released.
+    ///
+    /// Evicted values may have `Drop` impls that access caches on this pool, which would deadlock
+    /// if they ran while the lock is held.
+    fn with_inner<R>(&self, f: impl FnOnce(&mut InnerShared) -> R) -> R {
+        let mut inner = self.inner.lock().unwrap();
+        let eviction_starts = inner.allocator.eviction_starts();
+        let result = f(&mut inner);
+        let evicted = std::mem::take(&mut inner.evicted);
+        let started_evicting = inner.allocator.eviction_starts() != eviction_starts;
+        drop(inner);
+
+        if started_evicting {
+            self.eviction_started.notify_all();
+        }
+        drop(evicted);
+        result
+    }
+
+    fn claim(&self, bytes: usize, holder: Weak<dyn EntryHolder>) -> Option<Claimed> {
+        self.claim_grouped(bytes, holder, None)
+    }
+
+    /// Like `claim`, but also joins the claimed id to `group` under the same lock, so no
+    /// eviction can separate it from its mates first.
+    fn claim_grouped(
+        &self,
+        bytes: usize,
+        holder: Weak<dyn EntryHolder>,
+        group: Option<u64>,
+    ) -> Option<Claimed> {
+        if !self.is_reasonable(bytes) {
+            return None;
+        }
+        self.with_inner(|inner| {
+            let before = inner.evictions.load(Ordering::SeqCst);
+            let id = inner.claim(bytes, holder)?;
+            if let Some(group) = group {
+                if !inner.allocator.set_group(id, group) {
+                    inner.release(id);
+                    return None;
+                }
+            }
+            Some(inner.claimed(id, before))
+        })
+    }
+
+    fn claim_bounded(
+        &self,
+        bytes: usize,
+        max_evict_bytes: usize,
+        holder: Weak<dyn EntryHolder>,
+    ) -> Option<Claimed> {
+        if !self.is_reasonable(bytes) {
+            return None;
+        }
+        self.with_inner(|inner| {
+            let before = inner.evictions.load(Ordering::SeqCst);
+            let id = inner.claim_bounded(bytes, max_evict_bytes, holder)?;
+            Some(inner.claimed(id, before))
+        })
+    }
+
+    /// Whether `claimed` was evicted before its value was stored, which the eviction could not
+    /// have seen. If so, the caller must remove the value itself.
+    fn was_lost(&self, claimed: &Claimed) -> bool {
+        if self.evictions.load(Ordering::SeqCst) == claimed.evictions {
+            return false;
+        }
+        let inner = self.inner.lock().unwrap();
+        !inner.allocator.contains(claimed.id)
+    }
+
+    /// Whether an entry whose key hashes to `key_hash` is accessed more often than the entry it
+    /// would evict to fit `bytes`.
+    fn admits(&self, sketch: &FrequencySketch, key_hash: u64, bytes: usize) -> bool {
+        let inner = self.inner.lock().unwrap();
+        if !inner.allocator.needs_room(bytes) {
+            return true;
+        }
+        let victim = match inner.allocator.oldest() {
+            Some(id) => id,
+            None => return true,
+        };
+        let victim_hash = inner
+            .entry_holders
+            .get(&victim)
+            .and_then(Weak::upgrade)
+            .and_then(|holder| holder.hash_of(victim));
+        match victim_hash {
+            Some(victim_hash) => sketch.estimate(key_hash) > sketch.estimate(victim_hash),
+            None => true,
+        }
+    }
+
+    /// Builds a value for `get_or_insert` and friends, waiting for a slot if the pool limits
+    /// concurrent construction.
+    fn construct<V>(&self, insert_with: impl FnOnce() -> V) -> V {
+        match &self.construction {
+            Some(limit) => limit.run(insert_with),
+            None => insert_with(),
+        }
+    }
+
+    /// Whether `bytes` is a size a correct `MemorySize` impl could plausibly have returned.
+    fn is_reasonable(&self, bytes: usize) -> bool {
+        debug_assert!(
+            bytes != usize::MAX,
+            "MemorySize returned usize::MAX, likely an overflow in a bytes() impl"
+        );
+        match self.options.max_reasonable_entry_bytes {
+            Some(max) => bytes <= max,
+            None => true,
+        }
+    }
+
+    fn touch(&self, id: EntryId) -> Option<usize> {
+        self.inner.lock().unwrap().touch(id)
+    }
+
+    /// Returns whether the missed key was evicted recently.
+    fn record_miss(&self, key_hash: impl FnOnce() -> u64) -> bool {
+        self.options.track_ghosts && self.inner.lock().unwrap().record_miss(key_hash())
+    }
+
+    /// A builder with this pool's policy and options, but default limits.
+    fn builder_like(&self) -> SharedLruBuilder {
+        let inner = self.inner.lock().unwrap();
+        let mut builder = SharedLru::builder()
+            .policy(self.options.policy)
+            .count_entry_overhead(self.options.count_entry_overhead)
+            .skip_busy_entries(inner.skip_busy_entries)
+            .recent_evictions(inner.recent_evictions_capacity)
+            .ghost_capacity(inner.ghost.as_ref().map_or(0, GhostList::capacity));
+        if let Some(bytes) = self.options.max_reasonable_entry_bytes {
+            builder = builder.max_reasonable_entry_bytes(bytes);
+        }
+        if let Some(n) = self.options.construction_concurrency {
+            builder = builder.construction_concurrency(n);
+        }
+        builder
+    }
+
+    /// Forgets that a key being cached again was evicted, so a later miss on it is not
+    /// mistaken for one a larger pool would have served.
+    fn forget_ghost(&self, key_hash: impl FnOnce() -> u64) {
+        if self.options.track_ghosts {
+            if let Some(ghost) = &mut self.inner.lock().unwrap().ghost {
+                ghost.remove(key_hash());
+            }
+        }
+    }
+
+    fn release(&self, id: EntryId) {
+        self.with_inner(|inner| inner.release(id))
+    }
+
+    fn resize(&self, id: EntryId, bytes: usize) -> bool {
+        self.with_inner(|inner| inner.resize(id, bytes))
+    }
+
+    /// Evicts the oldest entries across all caches until at most `target_bytes` are in use.
+    ///
+    /// Useful for responding to memory pressure signals from the platform.
+    pub fn trim_to(&self, target_bytes: usize) {
+        self.with_inner(|inner| inner.trim_to(target_bytes))
+    }
+
+    /// Like [`SharedLru::trim_to`], but the target is a fraction of the byte limit, matching
+    /// [`SharedLru::percent_used`]. `trim_to_percent(0.8)` frees up at least 20% of the pool.
+    ///
+    /// A NaN `fraction` is a bug in the caller: it panics in debug builds and evicts nothing in
+    /// release builds.
+    pub fn trim_to_percent(&self, fraction: f32) {
+        debug_assert!(!fraction.is_nan(), "trim_to_percent called with NaN");
+        if fraction.is_nan() {
+            return;
+        }
+        self.with_inner(|inner| {
+            let limit = inner.allocator.capacity() as f64;
+            inner.trim_to((limit * f64::from(fraction.clamp(0., 1.))) as usize)
+        })
+    }
+
+    /// Evicts every entry from every cache connected to this pool.
+    ///
+    /// Cleared entries are dropped, so a [`TieredCache`](crate::TieredCache) does not spill them.
+    pub fn clear_all(&self) {
+        self.with_inner(|inner| inner.clear_all())
+    }
+
+    /// Empties every cache and forgets all history, returning the pool to how it was when built.
+    ///
+    /// Unlike [`SharedLru::clear_all`], this also drops inserts that are still in flight, along
+    /// with ghost hits and admission statistics. The byte limit and callbacks are kept.
+    pub fn reset(&self) {
+        self.with_inner(|inner| inner.reset());
+        if let Some(sketch) = &self.sketch {
+            sketch.clear();
+        }
+    }
+
+    /// Receives an event for each entry evicted to make room from now on, to handle off the
+    /// eviction path.
+    ///
+    /// Up to 1024 events are buffered; while the receiver is that far behind, further
+    /// events are dropped rather than slowing down eviction. Entries removed by `clear_all` or
+    /// `pop` are not reported.
+    pub fn eviction_events(&self) -> Receiver<EvictionEvent> {
+        let (sender, receiver) = mpsc::sync_channel(EVENT_BUFFER);
+        self.inner.lock().unwrap().event_senders.push(sender);
+        receiver
+    }
+
+    /// The most recently evicted entries, oldest first, up to
+    /// [`SharedLruBuilder::recent_evictions`] of them. Always empty unless that is set.
+    ///
+    /// Like [`SharedLru::eviction_events`], only entries evicted to make room are recorded.
+    pub fn recent_evictions(&self) -> Vec<EvictionRecord> {
+        let inner = self.inner.lock().unwrap();
+        inner.recent_evictions.iter().cloned().collect()
+    }
+
+    /// Changes the pool's byte limit, evicting the oldest entries if it shrank below current usage.
+    pub fn set_byte_limit(&self, byte_limit: usize) {
+        self.with_inner(|inner| {
+            inner.allocator.set_capacity(byte_limit);
+            inner.trim_to(byte_limit);
+        })
+    }
+
+    /// Blocks until the pool is evicting to make room.
+    ///
+    /// Returns immediately if the pool is already evicting. Lets a controller thread rebalance
+    /// several pools with [`SharedLru::set_byte_limit`] without polling.
+    pub fn wait_for_eviction(&self) {
+        let inner = self.inner.lock().unwrap();
+        let starts = inner.allocator.eviction_starts();
+        let _inner = self
+            .eviction_started
+            .wait_while(inner, |inner| {
+                !inner.allocator.is_evicting() && inner.allocator.eviction_starts() == starts
+            })
+            .unwrap();
+    }
+
+    /// Like [`SharedLru::wait_for_eviction`], but gives up after `timeout`. Returns whether the pool
+    /// started evicting.
+    pub fn wait_for_eviction_timeout(&self, timeout: Duration) -> bool {
+        let inner = self.inner.lock().unwrap();
+        let starts = inner.allocator.eviction_starts();
+        let (_inner, result) = self
+            .eviction_started
+            .wait_timeout_while(inner, timeout, |inner| {
+                !inner.allocator.is_evicting() && inner.allocator.eviction_starts() == starts
+            })
+            .unwrap();
+        !result.timed_out()
+    }
+
+    /// Captures the size, recency and owning cache of every entry in the pool, for offline
+    /// analysis.
+    pub fn snapshot_metadata(&self) -> PoolSnapshot {
+        let inner = self.inner.lock().unwrap();
+        let entries = inner
+            .allocator
+            .oldest_first()
+            .enumerate()
+            .map(|(recency_rank, (id, bytes))| EntrySnapshot {
+                id,
+                bytes,
+                recency_rank,
+                cache: inner.owner_of(id),
+            })
+            .collect();
+        PoolSnapshot { entries }
+    }
+
+    /// How many live entries fall in each power-of-two size range, smallest first. Empty buckets
+    /// are left out.
+    pub fn size_histogram(&self) -> Vec<(SizeBucket, usize)> {
+        let mut counts = std::collections::BTreeMap::new();
+        let inner = self.inner.lock().unwrap();
+        for (_, bytes) in inner.allocator.oldest_first() {
+            *counts.entry(SizeBucket::of(bytes)).or_insert(0) += 1;
+        }
+        counts.into_iter().collect()
+    }
+
+    /// How many `get`s missed but would have hit if evicted entries had been kept.
+    ///
+    /// Always 0 unless ghost tracking is enabled with [`SharedLruBuilder::ghost_capacity`]. A high
+    /// count relative to total misses suggests a larger byte limit would help.
+    pub fn ghost_hits(&self) -> u64 {
+        let inner = self.inner.lock().unwrap();
+        inner.ghost.as_ref().map_or(0, GhostList::hits)
+    }
+
+    /// Evicts the entry with `id`, as if the pool had chosen it, returning whether it was present.
+    ///
+    /// For tools that pick victims from [`SharedLru::snapshot_metadata`]. An id that has since
+    /// been evicted is ignored.
+    pub fn evict_id(&self, id: EntryId) -> bool {
+        self.with_inner(|inner| {
+            if !inner.allocator.contains(id) {
+                return false;
+            }
+            inner.evict(id);
+            true
+        })
+    }
+
+    /// Shrinks the pool's bookkeeping to fit the entries it holds now, and forgets dropped
+    /// caches.
+    ///
+    /// After a burst of inserts followed by evictions the bookkeeping stays sized for the peak.
+    /// Call this during quiet periods on long-lived pools; it holds the lock for time linear in
+    /// the number of entries.
+    pub fn compact(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.allocator.compact();
+        inner.entry_holders.shrink_to_fit();
+        inner.forget_dropped_caches();
+        inner.caches.shrink_to_fit();
+        inner.quotas.shrink_to_fit();
+        inner.evicted.shrink_to_fit();
+    }
+
+    /// Panics if the pool's bookkeeping disagrees with itself or with its caches. Only holds while
+    /// no insert is in flight.
+    #[cfg(test)]
+    pub(crate) fn debug_invariants(&self) {
+        let inner = self.inner.lock().unwrap();
+        inner.allocator.debug_invariants();
+
+        let allocated = inner.allocator.oldest_first().collect::<HashMap<_, _>>();
+        let holders = inner.entry_holders.keys().copied().collect::<HashSet<_>>();
+        assert_eq!(
+            allocated.keys().copied().collect::<HashSet<_>>(),
+            holders,
+            "allocated ids and entry holders differ"
+        );
+
+        let mut stored = HashSet::new();
+        for cache in inner.caches.iter().filter_map(Weak::upgrade) {
+            for id in cache.debug_invariants() {
+                assert!(
+                    allocated.contains_key(&id),
+                    "{:?} is cached but not allocated",
+                    id
+                );
+                stored.insert(id);
+            }
+        }
+        assert_eq!(
+            stored, holders,
+            "allocated entries are missing from their caches"
+        );
+
+        for (key, quota) in &inner.quotas {
+            let used = inner
+                .entry_holders
+                .iter()
+                .filter(|(_, holder)| holder_key(holder) == *key)
+                .map(|(id, _)| allocated[id])
+                .sum::<usize>();
+            assert_eq!(quota.used, used, "quota usage drifted");
+        }
+    }
+
+    /// How many caches made from this pool are still alive.
+    pub fn cache_count(&self) -> usize {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .caches
+            .iter()
+            .filter(|cache| cache.strong_count() > 0)
+            .count()
+    }
+
+    /// Whether an entry of `bytes` could be inserted at all, evicting as needed.
+    ///
+    /// Walks the eviction order under the pool lock, so call this once per entry rather than in a
+    /// loop.
+    pub fn would_fit(&self, bytes: usize) -> bool {
+        self.is_reasonable(bytes)
+            && self
+                .inner
+                .lock()
+                .unwrap()
+                .allocator
+                .eviction_cost(bytes)
+                .is_some()
+    }
+
+    /// The name of the cache holding `id`, if it is still cached and the cache was made with
+    /// [`SharedLru::make_named_cache`].
+    pub fn owner_of(&self, id: EntryId) -> Option<String> {
+        self.inner.lock().unwrap().owner_of(id)
+    }
+
+    pub fn byte_limit(&self) -> usize {
+        self.inner.lock().unwrap().allocator.capacity()
+    }
+
+    pub fn percent_used(&self) -> f32 {
+        self.inner.lock().unwrap().allocator.percent_used()
+    }
+
+    /// Whether the pool is currently evicting entries to make room for new ones.
+    pub fn is_evicting(&self) -> bool {
+        self.inner.lock().unwrap().allocator.is_evicting()
+    }
+
+    /// Registers a callback that fires when the pool begins evicting entries.
+    ///
+    /// The callback runs while the pool is locked, so it should be lightweight and must not access
+    /// any cache connected to this pool.
+    pub fn on_eviction_start(&self, callback: impl FnMut() + Send + 'static) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.allocator.set_on_eviction_start(callback);
+    }
+
+    /// Registers a callback that fires when an insert takes usage to `fraction` of the byte limit
+    /// or more, as an early warning before the pool starts evicting. It fires again only after
+    /// usage drops 5 percentage points below `fraction`.
+    ///
+    /// The same restrictions as [`SharedLru::on_eviction_start`] apply.
+    pub fn on_usage_threshold(&self, fraction: f32, callback: impl FnMut() + Send + 'static) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.allocator.add_usage_threshold(fraction, callback);
+    }
+
+    /// Registers a callback that fires when the pool drops back below its low watermark and stops
+    /// evicting.
+    ///
+    /// The same restrictions as [`SharedLru::on_eviction_start`] apply.
+    pub fn on_eviction_stop(&self, callback: impl FnMut() + Send + 'static) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.allocator.set_on_eviction_stop(callback);
+    }
+}
+
+/// Every cache releases its entries when dropped, so by the time the pool goes nothing should be
+/// charged. Debug builds check that to catch accounting bugs.
+impl Drop for SharedLru {
+    fn drop(&mut self) {
+        #[cfg(debug_assertions)]
+        {
+            if std::thread::panicking() {
+                return;
+            }
+            let inner = self.inner.get_mut().unwrap_or_else(|e| e.into_inner());
+            let leaked = inner.allocator.used();
+            if leaked != 0 {
+                let ids = inner
+                    .allocator
+                    .oldest_first()
+                    .map(|(id, _)| id)
+                    .collect::<Vec<_>>();
+                panic!(
+                    "SharedLru dropped with {} bytes still charged: {:?}",
+                    leaked, ids
+                );
+            }
+        }
+    }
+}
+
+pub(crate) struct InnerShared {
+    allocator: Allocator,
+    entry_holders: HashMap<EntryId, Weak<dyn EntryHolder>, IdBuildHasher>,
+    caches: Vec<Weak<dyn EntryHolder>>,
+    evicted: Vec<Evicted>,
+    pub(crate) skip_busy_entries: bool,
+    pub(crate) ghost: Option<GhostList>,
+    evictions: Arc<AtomicU64>,
+    quotas: HashMap<usize, Quota>,
+    event_senders: Vec<SyncSender<EvictionEvent>>,
+    recent_evictions: VecDeque<EvictionRecord>,
+    pub(crate) recent_evictions_capacity: usize,
+}
+
+impl InnerShared {
+    pub(crate) fn new(allocator: Allocator) -> Self {
+        InnerShared {
+            allocator,
+            entry_holders: HashMap::default(),
+            caches: Vec::new(),
+            evicted: Vec::new(),
+            skip_busy_entries: false,
+            ghost: None,
+            evictions: Arc::new(AtomicU64::new(0)),
+            quotas: HashMap::new(),
+            event_senders: Vec::new(),
+            recent_evictions: VecDeque::new(),
+            recent_evictions_capacity: 0,
+        }
+    }
+
+    fn claimed(&self, id: EntryId, evictions_before: u64) -> Claimed {
+        let evictions = self.evictions.load(Ordering::SeqCst);
+        Claimed {
+            id,
+            evictions,
+            evicted: evictions != evictions_before,
+            headroom: self.headroom(),
+        }
+    }
+
+    fn headroom(&self) -> usize {
+        self.allocator
+            .capacity()
+            .saturating_sub(self.allocator.used())
+    }
+
+    fn register(&mut self, cache: Weak<dyn EntryHolder>) {
+        self.forget_dropped_caches();
+        self.caches.push(cache);
+    }
+
+    fn forget_dropped_caches(&mut self) {
+        // Dropping the last `Weak` lets a new cache reuse the address its quota is keyed by.
+        let quotas = &mut self.quotas;
+        self.caches.retain(|cache| {
+            let alive = cache.strong_count() > 0;
+            if !alive {
+                quotas.remove(&holder_key(cache));
+            }
+            alive
+        });
+    }
+
+    /// Evicts the cache's own oldest entries until `bytes` more fit in its quota, if it has one.
+    /// Returns false if they never could.
+    fn make_quota_room(&mut self, holder: &Weak<dyn EntryHolder>, bytes: usize) -> bool {
+        let key = holder_key(holder);
+        loop {
+            let quota = match self.quotas.get(&key) {
+                Some(quota) => quota,
+                None => return true,
+            };
+            if bytes > quota.max_bytes {
+                return false;
+            }
+            if quota.used + bytes <= quota.max_bytes {
+                return true;
+            }
+
+            let holders = &self.entry_holders;
+            let victim = self
+                .allocator
+                .victims()
+                .map(|(id, _)| id)
+                .find(|id| holders.get(id).is_some_and(|h| holder_key(h) == key));
+            match victim {
+                Some(id) => self.evict(id),
+                None => return false,
+            }
+        }
+    }
+
+    fn owner_of(&self, id: EntryId) -> Option<String> {
+        let holder = self.entry_holders.get(&id)?.upgrade()?;
+        holder.name().map(String::from)
+    }
+
+    /// How many bytes `make_quota_room` would evict from the cache to fit `bytes` in its quota,
+    /// including the group mates its victims take with them, or `None` if they never could fit.
+    fn quota_eviction_cost(&self, holder: &Weak<dyn EntryHolder>, bytes: usize) -> Option<usize> {
+        let key = holder_key(holder);
+        let quota = match self.quotas.get(&key) {
+            Some(quota) => quota,
+            None => return Some(0),
+        };
+        if bytes > quota.max_bytes {
+            return None;
+        }
+
+        let overflow = (quota.used + bytes).saturating_sub(quota.max_bytes);
+        let mut cost = 0;
+        let mut evicted = Vec::new();
+        for (victim, _) in self.allocator.victims() {
+            if cost >= overflow {
+                break;
+            }
+            let owned = self
+                .entry_holders
+                .get(&victim)
+                .is_some_and(|h| holder_key(h) == key);
+            if !owned || evicted.contains(&victim) {
+                continue;
+            }
+            let mates = self.allocator.group_mates(victim).into_iter();
+            for id in
+                core::iter::once(victim).chain(mates.filter(|&m| !self.allocator.is_pinned(m)))
+            {
+                if !evicted.contains(&id) {
+                    cost += self.allocator.bytes_of(id)?;
+                    evicted.push(id);
+                }
+            }
+        }
+        (cost >= overflow).then_some(cost)
+    }
+
+    /// Records that `id` now holds `bytes`, charging the quota of the cache it belongs to.
+    fn assign(&mut self, id: EntryId, bytes: usize, holder: Weak<dyn EntryHolder>) {
+        if let Some(quota) = self.quotas.get_mut(&holder_key(&holder)) {
+            quota.used += bytes;
+        }
+        self.entry_holders.insert(id, holder);
+    }
+
+    /// Frees `id` in the allocator and in its cache's quota. Call before removing its holder.
+    fn free(&mut self, id: EntryId) -> Option<usize> {
+        let bytes = self.allocator.free(id)?;
+        if !self.quotas.is_empty() {
+            self.adjust_quota(id, |used| used - bytes);
+        }
+        Some(bytes)
+    }
+
+    fn adjust_quota(&mut self, id: EntryId, f: impl FnOnce(usize) -> usize) {
+        let key = match self.entry_holders.get(&id) {
+            Some(holder) => holder_key(holder),
+            None => return,
+        };
+        if let Some(quota) = self.quotas.get_mut(&key) {
+            quota.used = f(quota.used);
+        }
+    }
+
+    fn claim(&mut self, bytes: usize, holder: Weak<dyn EntryHolder>) -> Option<EntryId> {
+        if !self.make_quota_room(&holder, bytes) {
+            return None;
+        }
+
+        let mut busy = Vec::new();
+        loop {
+            match self.allocator.try_alloc(bytes, &busy) {
+                AllocResult::Success(id) => {
+                    self.assign(id, bytes, holder);
+                    return Some(id);
+                }
+                AllocResult::Evict(id) if self.skip_busy_entries && !busy.contains(&id) => {
+                    if self.try_evict(id).is_err() {
+                        busy.push(id);
+                    }
+                }
+                AllocResult::Evict(id) => self.evict(id),
+                AllocResult::TooLarge => return None,
+            }
+        }
+    }
+
+    /// Claims `bytes`, evicting only what is needed to fit and at most `max_evict_bytes`.
+    fn claim_bounded(
+        &mut self,
+        bytes: usize,
+        max_evict_bytes: usize,
+        holder: Weak<dyn EntryHolder>,
+    ) -> Option<EntryId> {
+        // The quota's evictions free pool bytes too, so this total may overestimate but never
+        // undercounts.
+        let quota_cost = self.quota_eviction_cost(&holder, bytes)?;
+        if quota_cost + self.allocator.eviction_cost(bytes)? > max_evict_bytes {
+            return None;
+        }
+        if !self.make_quota_room(&holder, bytes) {
+            return None;
+        }
+
+        loop {
+            match self.allocator.try_alloc_exact(bytes) {
+                AllocResult::Success(id) => {
+                    self.assign(id, bytes, holder);
+                    return Some(id);
+                }
+                AllocResult::Evict(id) => self.evict(id),
+                AllocResult::TooLarge => return None,
+            }
+        }
+    }
+
+    fn evict(&mut self, id: EntryId) {
+        // Counted before looking for the value, so an insert that stores it concurrently sees
+        // the count change. See `SharedLru::was_lost`.
+        self.evictions.fetch_add(1, Ordering::SeqCst);
+        let mates = self.allocator.ungroup(id);
+        let bytes = self.free(id);
+        let holder = self
+            .entry_holders
+            .remove(&id)
+            .expect("should have entry holder for id");
+        if let Some(holder) = holder.upgrade() {
+            if let Some(evicted) = holder.evict(id) {
+                self.record_eviction(&*holder, evicted, bytes.unwrap_or(0));
+            }
+        }
+        self.evict_group_mates(mates);
+    }
+
+    /// Grouped entries are only useful together, so they're evicted together. Pinned members
+    /// stay, outside any group.
+    fn evict_group_mates(&mut self, mates: Vec<EntryId>) {
+        for mate in mates {
+            if self.allocator.contains(mate) && !self.allocator.is_pinned(mate) {
+                self.evict(mate);
+            }
+        }
+    }
+
+    fn record_eviction(&mut self, holder: &dyn EntryHolder, evicted: Evicted, bytes: usize) {
+        if let Some(ghost) = &mut self.ghost {
+            ghost.record(evicted.key_hash);
+        }
+        if !self.event_senders.is_empty() {
+            let event = EvictionEvent {
+                cache: holder.name().map(String::from),
+                key_hash: evicted.key_hash,
+                bytes,
+            };
+            // Never wait on a slow listener; a full channel just misses the event.
+            self.event_senders
+                .retain(|sender| match sender.try_send(event.clone()) {
+                    Ok(()) | Err(TrySendError::Full(_)) => true,
+                    Err(TrySendError::Disconnected(_)) => false,
+                });
+        }
+        if self.recent_evictions_capacity > 0 {
+            if self.recent_evictions.len() == self.recent_evictions_capacity {
+                self.recent_evictions.pop_front();
+            }
+            self.recent_evictions.push_back(EvictionRecord {
+                cache: holder.name().map(String::from),
+                key_hash: evicted.key_hash,
+                bytes,
+                at: Instant::now(),
+            });
+        }
+        self.evicted.push(evicted);
+    }
+
+    fn record_miss(&mut self, key_hash: u64) -> bool {
+        match &mut self.ghost {
+            Some(ghost) => ghost.record_miss(key_hash),
+            None => false,
+        }
+    }
+
+    /// Like `evict`, but gives up without changing anything if the entry is being read.
+    fn try_evict(&mut self, id: EntryId) -> Result<(), Busy> {
+        let holder = self
+            .entry_holders
+            .get(&id)
+            .expect("should have entry holder for id");
+        self.evictions.fetch_add(1, Ordering::SeqCst);
+        let holder = holder.upgrade();
+        let evicted = match &holder {
+            Some(arc) => arc.try_evict(id)?,
+            None => None,
+        };
+
+        let mates = self.allocator.ungroup(id);
+        let bytes = self.free(id);
+        self.entry_holders.remove(&id);
+        if let (Some(holder), Some(evicted)) = (holder, evicted) {
+            self.record_eviction(&*holder, evicted, bytes.unwrap_or(0));
+        }
+        self.evict_group_mates(mates);
+        Ok(())
+    }
+
+    fn release(&mut self, id: EntryId) {
+        self.free(id);
+        self.entry_holders.remove(&id);
+    }
+
+    /// Charges `id` for `bytes` instead of what it claimed, evicting other entries if it grew
+    /// past the limit. Returns whether `id` is still allocated afterwards.
+    fn resize(&mut self, id: EntryId, bytes: usize) -> bool {
+        let old = match self.allocator.resize(id, bytes) {
+            Some(old) => old,
+            None => return false,
+        };
+        self.adjust_quota(id, |used| used - old + bytes);
+
+        // Evicting one of `id`'s group mates would evict `id` too.
+        let mates = self.allocator.group_mates(id);
+        while self.allocator.used() > self.allocator.capacity() {
+            let victim = self
+                .allocator
+                .victims()
+                .map(|(victim, _)| victim)
+                .find(|victim| *victim != id && !mates.contains(victim));
+            match victim {
+                Some(victim) => self.evict(victim),
+                None => {
+                    self.evict(id);
+                    return false;
+                }
+            }
+        }
+        self.allocator.contains(id)
+    }
+
+    fn trim_to(&mut self, target_bytes: usize) {
+        while self.allocator.used() > target_bytes {
+            match self.allocator.oldest() {
+                Some(id) => self.evict(id),
+                None => return,
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.clear_all();
+
+        // Entries claimed but not yet stored survive `clear_all`. Counting this as an eviction
+        // makes their inserts notice the claim is gone and discard the value.
+        let claimed = self
+            .allocator
+            .oldest_first()
+            .map(|(id, _)| id)
+            .collect::<Vec<_>>();
+        if !claimed.is_empty() {
+            self.evictions.fetch_add(1, Ordering::SeqCst);
+        }
+        for id in claimed {
+            self.release(id);
+        }
+
+        self.allocator.compact();
+        self.entry_holders.shrink_to_fit();
+        self.evicted.shrink_to_fit();
+        if let Some(ghost) = &mut self.ghost {
+            ghost.clear();
+        }
+        self.recent_evictions.clear();
+    }
+
+    fn clear_all(&mut self) {
+        let caches = self
+            .caches
+            .iter()
+            .filter_map(Weak::upgrade)
+            .collect::<Vec<_>>();
+        for cache in caches {
+            for (id, evicted) in cache.clear() {
+                self.release(id);
+                self.evicted.push(evicted);
+            }
+        }
+
+        // Entries whose cache has been dropped have nobody left to clear them.
+        let orphaned = self
+            .entry_holders
+            .iter()
+            .filter(|(_, holder)| holder.strong_count() == 0)
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for id in orphaned {
+            self.release(id);
+        }
+    }
+
+    fn touch(&mut self, id: EntryId) -> Option<usize> {
+        self.allocator.set_newest(id)
+    }
+}
+
+pub struct LruCache<K, V> {
+    shared: Arc<SharedLru>,
+    entry_map: Arc<EntryMap<K, V>>,
+    large_value_warning: Option<LargeValueWarning<K>>,
+    touch_sampler: TouchSampler,
+    frozen: AtomicBool,
+    measure: Measure<K, V>,
+}
+
+/// How a cache charges entries against the pool. See [`LruCache::with_measure`].
+pub enum Measure<K, V> {
+    /// The keys' and values' [`MemorySize`], including what they own on the heap.
+    Deep,
+    /// Only `size_of` the key and value, ignoring anything they point to.
+    Shallow,
+    /// A caller-supplied cost, used as-is.
+    Custom(MeasureFn<K, V>),
+    /// Like `Deep`, but collections longer than `threshold` are estimated from `sample_size` of
+    /// their elements. See [`MemorySize::bytes_sampled`].
+    Sampled {
+        threshold: usize,
+        sample_size: usize,
+    },
+}
+
+pub type MeasureFn<K, V> = Arc<dyn Fn(&K, &V) -> usize + Send + Sync>;
+
+impl<K, V> Clone for Measure<K, V> {
+    fn clone(&self) -> Self {
+        match self {
+            Measure::Deep => Measure::Deep,
+            Measure::Shallow => Measure::Shallow,
+            Measure::Custom(measure) => Measure::Custom(Arc::clone(measure)),
+            Measure::Sampled {
+                threshold,
+                sample_size,
+            } => Measure::Sampled {
+                threshold: *threshold,
+                sample_size: *sample_size,
+            },
+        }
+    }
+}
+
+struct LargeValueWarning<K> {
+    fraction: f32,
+    describe: fn(&K) -> String,
+}
+
+impl<K, V> LruCache<K, V>
+where
+    K: MemorySize + Eq + Hash + Simple,
+    V: MemorySize + Simple,
+{
+    /// Flags inserts larger than `fraction` of the pool's byte limit.
+    ///
+    /// In debug builds an oversized insert panics with the key and size, so mistakes surface in
+    /// tests. In release builds a warning is logged and the insert proceeds.
+    pub fn with_large_value_warning(mut self, fraction: f32) -> Self
+    where
+        K: Debug,
+    {
+        self.large_value_warning = Some(LargeValueWarning {
+            fraction,
+            describe: |key| format!("{:?}", key),
+        });
+        self
+    }
+
+    /// Only update recency on this fraction of `get`s, chosen at random. Defaults to 1.0.
+    ///
+    /// Each recency update takes the pool lock, so very hot read paths can trade some precision
+    /// in eviction order for less lock traffic.
+    pub fn with_touch_probability(mut self, probability: f64) -> Self {
+        self.touch_sampler.set_probability(probability);
+        self
+    }
+
+    /// Changes how entries inserted from now on are charged. Defaults to [`Measure::Deep`].
+    pub fn with_measure(mut self, measure: Measure<K, V>) -> Self {
+        self.measure = measure;
+        self
+    }
+
+    pub fn insert(&self, key: K, value: V)
+    where
+        K: Clone,
+    {
+        self.insert_full(key, value);
+    }
+
+    /// Like [`LruCache::insert`], but reports what happened.
+    pub fn insert_full(&self, key: K, value: V) -> Inserted<V>
+    where
+        K: Clone,
+    {
+        let bytes = self.entry_bytes(&key, &value);
+        self.insert_reporting(key, value, bytes).0
+    }
+
+    /// Like [`LruCache::insert`], but charges the value as `value_bytes` instead of measuring it,
+    /// for bulk loads where sizes are already known, e.g. from a length-prefixed format.
+    ///
+    /// This only skips the measurement: the caller is responsible for `value_bytes` matching
+    /// what the cache's [`Measure`] would report, and [`LruCache::remeasure`] replaces it with
+    /// the measured size.
+    pub fn insert_presized(&self, key: K, value: V, value_bytes: usize) -> Inserted<V>
+    where
+        K: Clone,
+    {
+        let bytes = self.estimated_entry_bytes(&key, value_bytes);
+        self.insert_reporting(key, value, bytes).0
+    }
+
+    /// Like [`LruCache::insert`], but returns whether anything was evicted to make room. A cheap
+    /// signal of memory pressure.
+    pub fn insert_evicting(&self, key: K, value: V) -> bool
+    where
+        K: Clone,
+    {
+        let bytes = self.entry_bytes(&key, &value);
+        let (_, claimed) = self.insert_reporting(key, value, bytes);
+        claimed.is_some_and(|claimed| claimed.evicted)
+    }
+
+    /// Like [`LruCache::insert`], but joins the entry to `group`: touching any member of a group
+    /// promotes them all, and evicting any member evicts them all.
+    ///
+    /// For entries that are only useful as a set, like the tiles of an image. Groups are per
+    /// cache, and removing a member with `pop` leaves the rest cached.
+    pub fn insert_grouped(&self, group: impl Hash, key: K, value: V)
+    where
+        K: Clone,
+    {
+        let mut hasher = DefaultHasher::new();
+        self.entry_map.cache_id.hash(&mut hasher);
+        group.hash(&mut hasher);
+        let bytes = self.entry_bytes(&key, &value);
+        self.insert_into_group(key, value, bytes, Some(hasher.finish()));
+    }
+
+    /// Inserts, also returning the claim if one was made.
+    fn insert_reporting(&self, key: K, value: V, bytes: usize) -> (Inserted<V>, Option<Claimed>)
+    where
+        K: Clone,
+    {
+        self.insert_into_group(key, value, bytes, None)
+    }
+
+    /// Like `insert_reporting`, but joins the new entry to `group` as it is claimed.
+    fn insert_into_group(
+        &self,
+        key: K,
+        value: V,
+        bytes: usize,
+        group: Option<u64>,
+    ) -> (Inserted<V>, Option<Claimed>)
+    where
+        K: Clone,
+    {
+        if self.is_frozen() {
+            return (Inserted::Frozen(value), None);
+        }
+
+        // TODO(shelbyd): Remove clone here.
+        let as_trait: Weak<dyn EntryHolder> =
+            Arc::downgrade(&(Arc::clone(&self.entry_map) as Arc<dyn EntryHolder>));
+
+        self.warn_if_large(&key, bytes);
+        if !self.admits(&key, bytes) {
+            return (Inserted::Rejected(value), None);
+        }
+
+        let claimed = match self.shared.claim_grouped(bytes, as_trait, group) {
+            Some(claimed) => claimed,
+            None => return (Inserted::TooLarge(value), None),
+        };
+        let inserted = match self.store(claimed, key, value) {
+            Some(old) => Inserted::Replaced(old),
+            None => Inserted::New,
+        };
+        (inserted, Some(claimed))
+    }
+
+    /// Stores an entry under a freshly claimed id, returning the value it replaced.
+    fn store(&self, claimed: Claimed, key: K, value: V) -> Option<V>
+    where
+        K: Clone,
+    {
+        self.shared.forget_ghost(|| self.entry_map.key_hash(&key));
+        let replaced = self.entry_map.insert(claimed.id, key, value);
+        if let Some((old_id, _)) = &replaced {
+            self.shared.release(*old_id);
+        }
+        self.pin_if_frozen(claimed.id);
+        if self.shared.was_lost(&claimed) {
+            self.entry_map.remove(claimed.id);
+        }
+        replaced.map(|(_, old)| old)
+    }
+
+    /// Inserts only if doing so evicts at most `max_evict_bytes` worth of other entries.
+    ///
+    /// Returns the value back if it was too large, storing it would evict too much, or the
+    /// admission policy rejected it.
+    pub fn insert_bounded(&self, key: K, value: V, max_evict_bytes: usize) -> Result<(), V>
+    where
+        K: Clone,
+    {
+        if self.is_frozen() {
+            return Err(value);
+        }
+
+        let as_trait: Weak<dyn EntryHolder> =
+            Arc::downgrade(&(Arc::clone(&self.entry_map) as Arc<dyn EntryHolder>));
+
+        let bytes = self.entry_bytes(&key, &value);
+        self.warn_if_large(&key, bytes);
+        if !self.admits(&key, bytes) {
+            return Err(value);
+        }
+
+        match self.shared.claim_bounded(bytes, max_evict_bytes, as_trait) {
+            Some(claimed) => {
+                self.store(claimed, key, value);
+                Ok(())
+            }
+            None => Err(value),
+        }
+    }
+
+    /// Restores entries, hottest first, without evicting anything already in the pool. Returns
+    /// how many were stored.
+    ///
+    /// Entries are stored only while they fit in the free space, so with a snapshot larger than
+    /// the pool the hottest ones are kept. Afterwards the first entry stored is the newest.
+    pub fn warm_from(
+        &self,
+        entries: impl IntoIterator<Item = (K, V)>,
+        strategy: WarmStrategy,
+    ) -> usize
+    where
+        K: Clone,
+    {
+        let mut stored = Vec::new();
+        for (key, value) in entries {
+            match self.insert_bounded(key.clone(), value, 0) {
+                Ok(()) => stored.push(key),
+                Err(_) if strategy == WarmStrategy::StopWhenFull => break,
+                Err(_) => {}
+            }
+        }
+
+        stored.reverse();
+        self.touch_many(&stored);
+        stored.len()
+    }
+
+    /// Makes this cache read-only. Later inserts are ignored and return [`Inserted::Frozen`], and
+    /// the entries it holds now are never chosen for eviction by the pool.
+    ///
+    /// Frozen entries still count against the byte limit, so freezing a large share of the pool
+    /// leaves the remaining caches little room and can make them thrash.
+    pub fn freeze(&self) {
+        self.frozen.store(true, Ordering::Release);
+        self.shared.with_inner(|inner| {
+            for entry in self.entry_map.values.iter() {
+                inner.allocator.pin(*entry.key());
+            }
+        });
+    }
+
+    /// Protects the entry for `k` from eviction until the returned guard is dropped. Returns `None`
+    /// if `k` is not cached.
+    ///
+    /// Guards nest: the entry stays protected until every guard on it is dropped. Replacing or
+    /// popping the entry ends the protection early.
+    pub fn pin_temporarily<Q>(&self, k: &Q) -> Option<PinGuard<'_>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let id = self.entry_map.get_id(k)?;
+        let pinned = self.shared.with_inner(|inner| inner.allocator.pin(id));
+        pinned.then(|| PinGuard {
+            shared: &self.shared,
+            id,
+        })
+    }
+
+    /// Counts an access to `key` for the pool's admission policy, and checks whether the policy
+    /// lets it displace what fitting `bytes` would evict. Replacing an existing key is always
+    /// allowed.
+    fn admits(&self, key: &K, bytes: usize) -> bool {
+        let sketch = match &self.shared.sketch {
+            Some(sketch) => sketch,
+            None => return true,
+        };
+        let key_hash = self.entry_map.key_hash(key);
+        sketch.increment(key_hash);
+        self.entry_map.get_id(key).is_some() || self.shared.admits(sketch, key_hash, bytes)
+    }
+
+    fn is_frozen(&self) -> bool {
+        self.frozen.load(Ordering::Acquire)
+    }
+
+    /// Pins a just-stored entry if the cache froze after its insert checked. Either `freeze` sees
+    /// the entry in the map or this sees the flag, so no frozen entry is left evictable.
+    fn pin_if_frozen(&self, id: EntryId) {
+        if self.is_frozen() {
+            self.shared.with_inner(|inner| inner.allocator.pin(id));
+        }
+    }
+
+    /// The bytes inserting `key` and `value` would charge against the pool, for budgeting a batch
+    /// before inserting it.
+    pub fn measure(&self, key: &K, value: &V) -> usize {
+        self.entry_bytes(key, value)
+    }
+
+    /// The bytes an entry is charged against the pool.
+    fn entry_bytes(&self, key: &K, value: &V) -> usize {
+        let bytes = match &self.measure {
+            Measure::Deep => key.bytes().saturating_add(value.bytes()),
+            Measure::Shallow => size_of::<K>() + size_of::<V>(),
+            Measure::Custom(measure) => measure(key, value),
+            Measure::Sampled {
+                threshold,
+                sample_size,
+            } => key
+                .bytes_sampled(*threshold, *sample_size)
+                .saturating_add(value.bytes_sampled(*threshold, *sample_size)),
+        };
+        self.with_overhead(key, bytes)
+    }
+
+    /// Like `entry_bytes`, for a value expected to take `value_bytes` without measuring it.
+    fn estimated_entry_bytes(&self, key: &K, value_bytes: usize) -> usize {
+        let key_bytes = match &self.measure {
+            Measure::Deep => key.bytes(),
+            Measure::Shallow => size_of::<K>(),
+            Measure::Custom(_) => 0,
+            Measure::Sampled {
+                threshold,
+                sample_size,
+            } => key.bytes_sampled(*threshold, *sample_size),
+        };
+        self.with_overhead(key, key_bytes.saturating_add(value_bytes))
+    }
+
+    fn with_overhead(&self, key: &K, mut bytes: usize) -> usize {
+        if self.shared.options.count_entry_overhead {
+            bytes = bytes.saturating_add(Self::entry_overhead(key));
+        }
+        bytes
+    }
+
+    /// Bookkeeping each entry costs beyond its key and value.
+    fn entry_overhead(key: &K) -> usize {
+        // The id in `values`, plus the cloned key and id in `ids`.
+        let entry_map = size_of::<EntryId>() + key.bytes() + size_of::<EntryId>();
+        // The allocator's LRU node (id, size, two links) and its map slot.
+        let allocator = size_of::<EntryId>() + 4 * size_of::<usize>();
+        // The pool's id to holder map.
+        let holders = size_of::<EntryId>() + size_of::<Weak<dyn EntryHolder>>();
+
+        entry_map + allocator + holders
+    }
+
+    fn warn_if_large(&self, key: &K, bytes: usize) {
+        let warning = match &self.large_value_warning {
+            Some(warning) => warning,
+            None => return,
+        };
+
+        let limit = self.shared.byte_limit() as f32 * warning.fraction;
+        if bytes as f32 > limit {
+            let message = format!(
+                "Inserting {} bytes for key {}, more than {}% of the pool",
+                bytes,
+                (warning.describe)(key),
+                warning.fraction * 100.
+            );
+            debug_assert!(false, "{}", message);
+            log::warn!("{}", message);
+        }
+    }
+
+    pub fn get<Q>(&self, k: &Q) -> Option<ValueRef<'_, K, V>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.get_classified(k).0
+    }
+
+    /// Like [`LruCache::get`], but also says whether a miss is for a key the pool evicted
+    /// recently, which a larger pool might have kept.
+    ///
+    /// Telling the two kinds of miss apart needs [`SharedLruBuilder::ghost_capacity`]; without it
+    /// every miss is [`MissKind::Compulsory`].
+    pub fn get_classified<Q>(&self, k: &Q) -> (Option<ValueRef<'_, K, V>>, MissKind)
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.lookup(k, |id, sampled| {
+            if sampled {
+                self.shared.touch(id);
+            }
+        })
+    }
+
+    /// The lookup shared by `get` and its variants. `on_hit` is called with the entry's id and
+    /// whether [`LruCache::with_touch_probability`] sampled this access, and should touch it if so.
+    fn lookup<Q>(
+        &self,
+        k: &Q,
+        on_hit: impl FnOnce(EntryId, bool),
+    ) -> (Option<ValueRef<'_, K, V>>, MissKind)
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        if let Some(sketch) = &self.shared.sketch {
+            sketch.increment(self.entry_map.key_hash(k));
+        }
+
+        let entry = self.entry_map.get_id(k).and_then(|id| {
+            on_hit(id, self.touch_sampler.sample());
+            self.entry_map.get(k)
+        });
+        match entry {
+            Some(entry) => (Some(ValueRef { entry }), MissKind::Hit),
+            None if self.shared.record_miss(|| self.entry_map.key_hash(k)) => {
+                (None, MissKind::Capacity)
+            }
+            None => (None, MissKind::Compulsory),
+        }
+    }
+
+    /// Clones the values for `keys` as they all were at a single moment, with no insert or
+    /// eviction landing between lookups.
+    ///
+    /// Writers to the shards the keys live in wait until every value is cloned, so keep `keys`
+    /// short. Holding a [`ValueRef`] from this cache while calling this can deadlock.
+    pub fn consistent_get(&self, keys: &[K]) -> Vec<Option<V>>
+    where
+        V: Clone,
+    {
+        let found = self.entry_map.consistent_get(keys);
+        for (id, _) in found.iter().flatten() {
+            if self.touch_sampler.sample() {
+                self.shared.touch(*id);
+            }
+        }
+        found
+            .into_iter()
+            .map(|entry| entry.map(|(_, value)| value))
+            .collect()
+    }
+
+    /// Like [`LruCache::get`], but applies `f` to the value and releases the guard before
+    /// returning, so only the part of the value you need is kept.
+    pub fn get_project<Q, R>(&self, k: &Q, f: impl FnOnce(&V) -> R) -> Option<R>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.get(k).map(|value| f(&value))
+    }
+
+    /// Like [`LruCache::get`], but leaves recency alone, for entries that were just inserted and
+    /// are already the newest.
+    fn get_untouched<Q>(&self, k: &Q) -> Option<ValueRef<'_, K, V>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        Some(ValueRef {
+            entry: self.entry_map.get(k)?,
+        })
+    }
+
+    /// Like [`LruCache::get`], but also returns the bytes the entry was charged when inserted.
+    pub fn get_with_size<Q>(&self, k: &Q) -> Option<(ValueRef<'_, K, V>, usize)>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let bytes = self.shared.touch(self.entry_map.get_id(k)?)?;
+
+        let value = ValueRef {
+            entry: self.entry_map.get(k)?,
+        };
+        Some((value, bytes))
+    }
+
+    /// The bytes the entry for `k` is charged against the pool. Does not count as a use.
+    pub(crate) fn charged_bytes<Q>(&self, k: &Q) -> Option<usize>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let id = self.entry_map.get_id(k)?;
+        self.shared.inner.lock().unwrap().allocator.bytes_of(id)
+    }
+
+    /// Marks every cached key in `keys` as used, taking the pool lock once. Later keys end up
+    /// newer. Keys that aren't cached are ignored.
+    ///
+    /// For access tracked outside the cache, where a full `get` per key would be wasted work.
+    pub fn touch_many(&self, keys: &[K]) {
+        let ids = keys
+            .iter()
+            .filter_map(|key| self.entry_map.get_id(key))
+            .collect::<Vec<_>>();
+        let mut inner = self.shared.inner.lock().unwrap();
+        for id in ids {
+            inner.touch(id);
+        }
+    }
+
+    /// Where the entry for `k` is in the whole pool's recency order: 0 is the least recently used
+    /// and the newest entry is one less than the pool's entry count. Does not count as a use.
+    ///
+    /// This is LRU position, not eviction order: pinned entries are counted, and
+    /// [`Policy::LfuWithAging`] evicts by frequency instead.
+    ///
+    /// Takes time linear in the number of entries in the pool, so it is meant for debugging.
+    pub fn recency_rank<Q>(&self, k: &Q) -> Option<usize>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let id = self.entry_map.get_id(k)?;
+        self.shared.inner.lock().unwrap().allocator.recency_rank(id)
+    }
+
+    /// Moves every entry of `other` into this cache, such as when merging shards.
+    ///
+    /// Entries from both caches are interleaved by their relative place in their pool's eviction
+    /// order, so the merged cache ages roughly as if it had always been one. When both hold a key,
+    /// the more recently used value is kept. The merged entries all end up newer than other
+    /// caches' entries in the pool.
+    pub fn merge_from(&self, other: LruCache<K, V>)
+    where
+        K: Clone,
+    {
+        let ours = self.keys_by_position();
+        let theirs = other.keys_by_position();
+        let mut values = other.into_hashmap();
+
+        let our_positions = ours.iter().cloned().map(|(position, key)| (key, position));
+        let our_positions = our_positions.collect::<HashMap<_, _>>();
+        let mut moved = Vec::new();
+        for (position, key) in theirs {
+            if our_positions.get(&key).is_some_and(|ours| *ours > position) {
+                continue;
+            }
+            if let Some(value) = values.remove(&key) {
+                self.insert(key.clone(), value);
+                moved.push((position, key));
+            }
+        }
+
+        let replaced = moved.iter().map(|(_, key)| key).collect::<HashSet<_>>();
+        let mut merged = ours
+            .iter()
+            .filter(|(_, key)| !replaced.contains(key))
+            .chain(&moved)
+            .cloned()
+            .collect::<Vec<_>>();
+        merged.sort_by(|(a, _), (b, _)| a.total_cmp(b));
+        let keys = merged.into_iter().map(|(_, key)| key).collect::<Vec<_>>();
+        self.touch_many(&keys);
+    }
+
+    /// Every key with how far along the pool's eviction order it is, from 0 for the oldest of this
+    /// cache's entries towards 1 for the newest.
+    fn keys_by_position(&self) -> Vec<(f64, K)>
+    where
+        K: Clone,
+    {
+        let inner = self.shared.inner.lock().unwrap();
+        let keys = inner
+            .allocator
+            .oldest_first()
+            .filter_map(|(id, _)| Some(self.entry_map.values.get(&id)?.0.clone()))
+            .collect::<Vec<_>>();
+        let count = keys.len() as f64;
+        keys.into_iter()
+            .enumerate()
+            .map(|(i, key)| ((i as f64 + 0.5) / count, key))
+            .collect()
+    }
+
+    /// Copies the entries for `keys` into a cache on a new pool sized to hold exactly them, e.g. to
+    /// move a tenant's working set elsewhere. Later keys end up newer; keys that aren't cached are
+    /// skipped.
+    ///
+    /// Values are cloned, and this cache is left as it was, recency included. The new cache keeps
+    /// this cache's [`Measure`] and the new pool this pool's policy and options, but not its
+    /// limits: the fork has no entry limit or minimum residency, and the default low watermark.
+    pub fn fork_subset(&self, keys: &[K]) -> (Arc<SharedLru>, LruCache<K, V>)
+    where
+        K: Clone,
+        V: Clone,
+    {
+        let entries = keys
+            .iter()
+            .filter_map(|key| Some((key.clone(), V::clone(&*self.get_untouched(key)?))))
+            .collect::<Vec<_>>();
+
+        let shared = self.shared.builder_like().byte_limit(0).build();
+        let cache = shared.make_cache().with_measure(self.measure.clone());
+        let bytes = entries
+            .iter()
+            .map(|(key, value)| cache.measure(key, value))
+            .fold(0, usize::saturating_add);
+        shared.set_byte_limit(bytes);
+        for (key, value) in entries {
+            cache.insert(key, value);
+        }
+        (shared, cache)
+    }
+
+    /// Moves every entry into a plain map, releasing all of their bytes back to the pool.
+    pub fn into_hashmap(self) -> HashMap<K, V> {
+        let ids = self
+            .entry_map
+            .values
+            .iter()
+            .map(|entry| *entry.key())
+            .collect::<Vec<_>>();
+        let entries = ids
+            .into_iter()
+            .filter_map(|id| Some((id, self.entry_map.remove(id)?)))
+            .collect::<Vec<_>>();
+        self.shared.with_inner(|inner| {
+            for (id, _) in &entries {
+                inner.release(*id);
+            }
+        });
+        entries.into_iter().map(|(_, entry)| entry).collect()
+    }
+
+    /// Removes the entry for `k` and returns its value, freeing its bytes.
+    ///
+    /// Concurrent `get`s see either the whole entry or nothing.
+    pub fn pop<Q>(&self, k: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let (id, _, value) = self.entry_map.take(k)?;
+        self.shared.release(id);
+        Some(value)
+    }
+
+    /// Like [`LruCache::pop`], but only removes the entry if `f` accepts its value.
+    pub fn pop_if<Q>(&self, k: &Q, f: impl FnOnce(&V) -> bool) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let (id, _, value) = self.entry_map.take_if(k, f)?;
+        self.shared.release(id);
+        Some(value)
+    }
+
+    /// Removes every cached key in `keys`, taking the pool lock once, and returns how many were
+    /// removed.
+    pub fn remove_many(&self, keys: &[K]) -> usize {
+        let removed = keys
+            .iter()
+            .filter_map(|key| self.entry_map.take(key))
+            .collect::<Vec<_>>();
+        self.shared.with_inner(|inner| {
+            for (id, _, _) in &removed {
+                inner.release(*id);
+            }
+        });
+        removed.len()
+    }
+
+    /// Recomputes the size of the entry for `k` and updates the pool's accounting to match.
+    ///
+    /// Call this after mutating a value through interior mutability. If the entry grew, older
+    /// entries are evicted to make room, or the entry itself if it no longer fits. Returns whether
+    /// the entry is still cached.
+    pub fn remeasure<Q>(&self, k: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let (id, bytes) = match self.entry_map.get(k) {
+            Some(entry) => (*entry.key(), self.entry_bytes(&entry.0, &entry.1)),
+            None => return false,
+        };
+        self.shared.resize(id, bytes)
+    }
+
+    /// Swaps in `value` for `k` and returns the old value, but only if `k` is already cached.
+    /// Unlike [`LruCache::insert`], a missing key stays missing and `value` is dropped.
+    ///
+    /// The entry keeps its id and counts as used; its size is updated to the new value's.
+    pub fn replace<Q>(&self, k: &Q, value: V) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        if self.is_frozen() {
+            return None;
+        }
+
+        let id = self.entry_map.get_id(k)?;
+        let (old, bytes) = {
+            let mut entry = self.entry_map.values.get_mut(&id)?;
+            let (key, current) = &mut *entry;
+            let old = std::mem::replace(current, value);
+            (old, self.entry_bytes(key, current))
+        };
+        self.shared.with_inner(|inner| {
+            inner.touch(id);
+            inner.resize(id, bytes);
+        });
+        Some(old)
+    }
+
+    /// Visits every entry in this cache in one pass under the pool lock, evicting those `f`
+    /// returns [`Keep::No`] for.
+    ///
+    /// `f` must not access caches on this pool, as the lock is held while it runs.
+    pub fn maintain(&self, mut f: impl FnMut(&K, &V, EntryMeta) -> Keep) {
+        self.shared.with_inner(|inner| {
+            let metas = inner
+                .allocator
+                .oldest_first()
+                .enumerate()
+                .map(|(recency_rank, (id, bytes))| {
+                    let meta = EntryMeta {
+                        bytes,
+                        recency_rank,
+                    };
+                    (id, meta)
+                })
+                .collect::<HashMap<_, _>>();
+
+            let doomed = self
+                .entry_map
+                .values
+                .iter()
+                .filter_map(|entry| {
+                    let id = *entry.key();
+                    let (key, value) = entry.value();
+                    match f(key, value, *metas.get(&id)?) {
+                        Keep::Yes => None,
+                        Keep::No => Some(id),
+                    }
+                })
+                .collect::<Vec<_>>();
+            for id in doomed {
+                inner.evict(id);
+            }
+        });
+    }
+
+    /// Applies `f` to the value of each cached key in `keys`, then marks them used, re-measures
+    /// them and updates the pool's accounting under a single lock acquisition. Keys that aren't
+    /// cached are skipped.
+    ///
+    /// Values are handed to `f` one at a time rather than as simultaneous `&mut` references,
+    /// since two keys can share a shard of the underlying map and locking it twice would deadlock.
+    pub fn update_many(&self, keys: &[K], mut f: impl FnMut(&K, &mut V)) {
+        let mut sizes = Vec::with_capacity(keys.len());
+        for key in keys {
+            let id = match self.entry_map.get_id(key) {
+                Some(id) => id,
+                None => continue,
+            };
+            if let Some(mut entry) = self.entry_map.values.get_mut(&id) {
+                let (key, value) = &mut *entry;
+                f(key, value);
+                sizes.push((id, self.entry_bytes(key, value)));
+            }
+        }
+
+        self.shared.with_inner(|inner| {
+            for (id, bytes) in sizes {
+                inner.touch(id);
+                inner.resize(id, bytes);
+            }
+        });
+    }
+
+    /// Returns an `Option` because the resulting value may be too large to fit inside the
+    /// allowed space. If the value is small enough, this will always return Some.
+    pub fn get_or_insert(&self, k: K, insert_with: impl FnOnce() -> V) -> Option<ValueRef<'_, K, V>>
+    where
+        K: Clone,
+    {
+        match self.get(&k) {
+            Some(ret) => Some(ret),
+            None => {
+                self.insert(k.clone(), self.shared.construct(insert_with));
+                self.get_untouched(&k)
+            }
+        }
+    }
+
+    /// Like [`LruCache::get_or_insert`], but takes the key by reference and only clones it on a
+    /// miss.
+    pub fn get_or_insert_ref(
+        &self,
+        k: &K,
+        insert_with: impl FnOnce() -> V,
+    ) -> Option<ValueRef<'_, K, V>>
+    where
+        K: Clone,
+    {
+        match self.get(k) {
+            Some(ret) => Some(ret),
+            None => {
+                self.insert(k.clone(), self.shared.construct(insert_with));
+                self.get_untouched(k)
+            }
+        }
+    }
+
+    /// Like [`LruCache::get_or_insert`], but skips running `insert_with` on a miss if a value of
+    /// `estimated_bytes` could not fit in the pool.
+    ///
+    /// The estimate only gates the closure; the inserted value is still charged its measured size.
+    /// With [`Measure::Custom`] the estimate should cover the key as well.
+    pub fn get_or_insert_sized(
+        &self,
+        k: K,
+        estimated_bytes: usize,
+        insert_with: impl FnOnce() -> V,
+    ) -> Option<ValueRef<'_, K, V>>
+    where
+        K: Clone,
+    {
+        if let Some(ret) = self.get(&k) {
+            return Some(ret);
+        }
+
+        if !self
+            .shared
+            .would_fit(self.estimated_entry_bytes(&k, estimated_bytes))
+        {
+            return None;
+        }
+
+        self.insert(k.clone(), self.shared.construct(insert_with));
+        self.get_untouched(&k)
+    }
+
+    /// Like [`LruCache::get_or_insert`], but only caches the new value if `should_cache` accepts
+    /// it, e.g. to avoid caching empty results. Values that aren't cached, including ones the
+    /// pool refuses, are returned as [`Fetched::Uncached`].
+    ///
+    /// Returns `None` only if the cached value was evicted before it could be read back.
+    pub fn get_or_insert_if(
+        &self,
+        k: K,
+        insert_with: impl FnOnce() -> V,
+        should_cache: impl FnOnce(&V) -> bool,
+    ) -> Option<Fetched<'_, K, V>>
+    where
+        K: Clone,
+    {
+        if let Some(ret) = self.get(&k) {
+            return Some(Fetched::Cached(ret));
+        }
+
+        let value = self.shared.construct(insert_with);
+        if !should_cache(&value) {
+            return Some(Fetched::Uncached(value));
+        }
+        match self.insert_full(k.clone(), value) {
+            Inserted::New | Inserted::Replaced(_) => self.get_untouched(&k).map(Fetched::Cached),
+            Inserted::TooLarge(value) | Inserted::Frozen(value) | Inserted::Rejected(value) => {
+                Some(Fetched::Uncached(value))
+            }
+        }
+    }
+
+    /// Like [`LruCache::get_or_insert`], but if another thread inserts `k` while `insert_with`
+    /// runs, keeps and returns that value instead of overwriting it.
+    pub fn get_or_insert_keep_existing(
+        &self,
+        k: K,
+        insert_with: impl FnOnce() -> V,
+    ) -> Option<ValueRef<'_, K, V>>
+    where
+        K: Clone,
+    {
+        if let Some(ret) = self.get(&k) {
+            return Some(ret);
+        }
+
+        let value = self.shared.construct(insert_with);
+        if self.is_frozen() {
+            return None;
+        }
+        let as_trait: Weak<dyn EntryHolder> =
+            Arc::downgrade(&(Arc::clone(&self.entry_map) as Arc<dyn EntryHolder>));
+        let bytes = self.entry_bytes(&k, &value);
+        self.warn_if_large(&k, bytes);
+        if self.admits(&k, bytes) {
+            if let Some(claimed) = self.shared.claim(bytes, as_trait) {
+                match self.entry_map.insert_vacant(claimed.id, k.clone(), value) {
+                    Ok(()) => {
+                        self.pin_if_frozen(claimed.id);
+                        self.shared.forget_ghost(|| self.entry_map.key_hash(&k));
+                        if self.shared.was_lost(&claimed) {
+                            self.entry_map.remove(claimed.id);
+                        }
+                    }
+                    Err(_) => self.shared.release(claimed.id),
+                }
+            }
+        }
+        self.get_untouched(&k)
+    }
+
+    /// Like [`LruCache::get_or_insert`], but also returns how many bytes the pool had free,
+    /// read under the same lock as the hit's recency update or the miss's claim.
+    ///
+    /// For ingestion that adapts to pressure, e.g. flushing a batch once headroom runs low.
+    pub fn get_or_insert_with_headroom(
+        &self,
+        k: K,
+        insert_with: impl FnOnce() -> V,
+    ) -> (Option<ValueRef<'_, K, V>>, usize)
+    where
+        K: Clone,
+    {
+        let mut headroom = None;
+        let (found, _) = self.lookup(&k, |id, sampled| {
+            headroom = Some(self.shared.with_inner(|inner| {
+                if sampled {
+                    inner.touch(id);
+                }
+                inner.headroom()
+            }));
+        });
+        if let (Some(value), Some(headroom)) = (found, headroom) {
+            return (Some(value), headroom);
+        }
+
+        let value = self.shared.construct(insert_with);
+        let bytes = self.entry_bytes(&k, &value);
+        let headroom = match self.insert_reporting(k.clone(), value, bytes) {
+            (_, Some(claimed)) => claimed.headroom,
+            (_, None) => self.shared.with_inner(|inner| inner.headroom()),
+        };
+        (self.get_untouched(&k), headroom)
+    }
+
+    /// Like [`LruCache::get_or_insert`], but returns a clone of the value so no guard is held
+    /// once this returns.
+    pub fn get_or_insert_cloned(&self, k: K, insert_with: impl FnOnce() -> V) -> Option<V>
+    where
+        K: Clone,
+        V: Clone,
+    {
+        self.get_or_insert(k, insert_with)
+            .map(|value| V::clone(&value))
+    }
+}
+
+/// Gives the cache's bytes back to the pool rather than waiting for them to be evicted.
+impl<K, V> Drop for LruCache<K, V> {
+    fn drop(&mut self) {
+        let ids = self
+            .entry_map
+            .values
+            .iter()
+            .map(|entry| *entry.key())
+            .collect::<Vec<_>>();
+        self.shared.with_inner(|inner| {
+            for id in ids {
+                inner.release(id);
+            }
+        });
+    }
+}
+
+impl<K, V> LruCache<K, Arc<V>>
+where
+    K: MemorySize + Eq + Hash + Simple,
+    V: MemorySize + Simple,
+{
+    /// Like [`LruCache::get`], but returns a clone of the stored `Arc` so no guard is held.
+    ///
+    /// The returned handle is `Send` and can be held across `.await` points.
+    pub fn get_arc<Q>(&self, k: &Q) -> Option<Arc<V>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.get(k).map(|value| Arc::clone(&value))
+    }
+}
+
+/// Keeps an entry from being evicted while held. See [`LruCache::pin_temporarily`].
+#[must_use = "the entry is only protected while the guard is held"]
+pub struct PinGuard<'c> {
+    shared: &'c SharedLru,
+    id: EntryId,
+}
+
+impl Drop for PinGuard<'_> {
+    fn drop(&mut self) {
+        let id = self.id;
+        self.shared.with_inner(|inner| inner.allocator.unpin(id));
+    }
+}
+
+/// The outcome of [`LruCache::insert_full`].
+#[derive(Debug, PartialEq, Eq)]
+pub enum Inserted<V> {
+    /// The key was not cached before.
+    New,
+    /// The key was cached; this is the value it had.
+    Replaced(V),
+    /// The value can never fit in the pool and was not stored.
+    TooLarge(V),
+    /// The cache is frozen and the value was not stored.
+    Frozen(V),
+    /// The pool's [`Policy`] judged the value less valuable than what it would evict.
+    Rejected(V),
+}
+
+/// How a [`LruCache::get_classified`] lookup went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MissKind {
+    /// The key was cached.
+    Hit,
+    /// The key was evicted recently, so more room might have turned this into a hit.
+    Capacity,
+    /// The key was not cached recently, or was removed rather than evicted.
+    Compulsory,
+}
+
+/// An entry evicted from the pool. See [`SharedLru::eviction_events`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EvictionEvent {
+    /// The name of the cache it was in, if made with [`SharedLru::make_named_cache`].
+    pub cache: Option<String>,
+    /// The hash of its key, as used for ghost and admission tracking.
+    pub key_hash: u64,
+    /// The bytes it was charged.
+    pub bytes: usize,
+}
+
+/// An entry evicted from the pool. See [`SharedLru::recent_evictions`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EvictionRecord {
+    /// The name of the cache it was in, if made with [`SharedLru::make_named_cache`].
+    pub cache: Option<String>,
+    /// The hash of its key, as used for ghost and admission tracking.
+    pub key_hash: u64,
+    /// The bytes it was charged.
+    pub bytes: usize,
+    /// When it was evicted.
+    pub at: Instant,
+}
+
+/// What [`LruCache::maintain`] knows about an entry besides its key and value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EntryMeta {
+    /// The bytes the entry is charged against the pool.
+    pub bytes: usize,
+    /// Position in the whole pool from least recently used, starting at 0. This is LRU position,
+    /// not eviction order: pinned entries are counted, and [`Policy::LfuWithAging`] evicts by
+    /// frequency instead.
+    pub recency_rank: usize,
+}
+
+/// What [`LruCache::warm_from`] does with an entry that doesn't fit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WarmStrategy {
+    /// Skip it and keep going, in case later, smaller entries fit.
+    NewestFirst,
+    /// Stop, keeping only the hottest prefix of the entries.
+    StopWhenFull,
+}
+
+/// Whether [`LruCache::maintain`] should keep an entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Keep {
+    Yes,
+    No,
+}
+
+/// A guard giving access to a cached value.
+///
+/// Holding a `ValueRef` while inserting into the same cache can deadlock if the insert needs to
+/// evict an entry stored near this one, so drop it first.
+pub struct ValueRef<'d, K, V> {
+    entry: Ref<'d, EntryId, (K, V), IdBuildHasher>,
+}
+
+impl<'d, K, V> core::ops::Deref for ValueRef<'d, K, V> {
+    type Target = V;
+
+    fn deref(&self) -> &Self::Target {
+        &self.entry.deref().1
+    }
+}
+
+/// The value from [`LruCache::get_or_insert_if`], either from the cache or handed back uncached.
+pub enum Fetched<'d, K, V> {
+    Cached(ValueRef<'d, K, V>),
+    Uncached(V),
+}
+
+impl<'d, K, V> core::ops::Deref for Fetched<'d, K, V> {
+    type Target = V;
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            Fetched::Cached(value) => value,
+            Fetched::Uncached(value) => value,
+        }
+    }
+}
+
+pub trait Simple: Send + Sync + 'static {}
+
+impl<T> Simple for T where T: Send + Sync + 'static {}
+
+/// Limits how many bytes one cache may hold.
+struct Quota {
+    max_bytes: usize,
+    used: usize,
+}
+
+/// Identifies the cache a holder belongs to, for as long as any `Weak` to it exists.
+fn holder_key(holder: &Weak<dyn EntryHolder>) -> usize {
+    holder.as_ptr() as *const () as usize
+}
+
+/// A removed entry, kept alive until it can be dropped outside the pool lock.
+struct Evicted {
+    key_hash: u64,
+    _entry: Box<dyn Send>,
+}
+
+/// Called with each entry evicted from a cache, once the pool lock has been released.
+pub(crate) type EvictHook<K, V> = Arc<dyn Fn(K, V) + Send + Sync>;
+
+/// Hands an evicted entry to its cache's hook when dropped, so the hook runs outside the lock.
+struct Spill<K, V> {
+    entry: Option<(K, V)>,
+    hook: EvictHook<K, V>,
+}
+
+impl<K, V> Drop for Spill<K, V> {
+    fn drop(&mut self) {
+        if let Some((key, value)) = self.entry.take() {
+            (self.hook)(key, value);
+        }
+    }
+}
+
+/// An entry could not be evicted without waiting on a reader.
+struct Busy;
+
+trait EntryHolder: Simple {
+    fn evict(&self, id: EntryId) -> Option<Evicted>;
+
+    /// Like `evict`, but fails instead of blocking if a `ValueRef` is held near the entry.
+    fn try_evict(&self, id: EntryId) -> Result<Option<Evicted>, Busy>;
+
+    /// Removes every entry, returning each id along with its removed entry.
+    fn clear(&self) -> Vec<(EntryId, Evicted)>;
+
+    /// The hash of the key stored for `id`, as used by the admission policy.
+    fn hash_of(&self, id: EntryId) -> Option<u64>;
+
+    fn name(&self) -> Option<&str>;
+
+    /// Panics if the holder's maps disagree with each other, and returns the ids it stores.
+    #[cfg(test)]
+    fn debug_invariants(&self) -> Vec<EntryId>;
+}
+
+impl<K, V> EntryHolder for EntryMap<K, V>
+where
+    K: Eq + Hash + Simple,
+    V: Simple,
+{
+    fn evict(&self, id: EntryId) -> Option<Evicted> {
+        let (key, value) = self.remove(id)?;
+        Some(self.evicted(key, value))
+    }
+
+    fn try_evict(&self, id: EntryId) -> Result<Option<Evicted>, Busy> {
+        let entry = self.try_remove(id)?;
+        Ok(entry.map(|(key, value)| self.evicted(key, value)))
+    }
+
+    fn clear(&self) -> Vec<(EntryId, Evicted)> {
+        let ids = self.values.iter().map(|e| *e.key()).collect::<Vec<_>>();
+        // Cleared entries are dropped rather than handed to the eviction hook.
+        ids.into_iter()
+            .filter_map(|id| {
+                let (key, value) = self.remove(id)?;
+                let evicted = Evicted {
+                    key_hash: self.key_hash(&key),
+                    _entry: Box::new((key, value)),
+                };
+                Some((id, evicted))
+            })
+            .collect()
+    }
+
+    fn hash_of(&self, id: EntryId) -> Option<u64> {
+        let entry = self.values.get(&id)?;
+        Some(self.key_hash(&entry.0))
+    }
+
+    fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    #[cfg(test)]
+    fn debug_invariants(&self) -> Vec<EntryId> {
+        for entry in self.ids.iter() {
+            assert!(
+                self.values.contains_key(entry.value()),
+                "a key maps to missing entry {:?}",
+                entry.value()
+            );
+        }
+        assert_eq!(self.ids.len(), self.values.len(), "an entry has no key");
+        self.values.iter().map(|entry| *entry.key()).collect()
+    }
+}
+
+/// Source of [`EntryMap::cache_id`]s. Unlike addresses, ids are never reused by a later cache.
+static NEXT_CACHE_ID: AtomicU64 = AtomicU64::new(0);
+
+pub struct EntryMap<K, V> {
+    values: DashMap<EntryId, (K, V), IdBuildHasher>,
+    ids: DashMap<K, EntryId>,
+    cache_id: u64,
+    on_evict: Option<EvictHook<K, V>>,
+    name: Option<String>,
+}
+
+impl<K, V> EntryMap<K, V>
+where
+    K: Eq + Hash + Simple,
+    V: Simple,
+{
+    fn evicted(&self, key: K, value: V) -> Evicted {
+        let key_hash = self.key_hash(&key);
+        let entry: Box<dyn Send> = match &self.on_evict {
+            Some(hook) => Box::new(Spill {
+                entry: Some((key, value)),
+                hook: Arc::clone(hook),
+            }),
+            None => Box::new((key, value)),
+        };
+        Evicted {
+            key_hash,
+            _entry: entry,
+        }
+    }
+}
+
+impl<K, V> EntryMap<K, V>
+where
+    K: Eq + Hash,
+{
+    /// Hashes `key` together with this map's identity, so equal keys in different caches differ.
+    fn key_hash<Q>(&self, key: &Q) -> u64
+    where
+        K: Borrow<Q>,
+        Q: Hash + ?Sized,
+    {
+        let mut hasher = DefaultHasher::new();
+        self.cache_id.hash(&mut hasher);
+        key.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Stores the entry, returning the one it replaced unless that was already evicted.
+    fn insert(&self, id: EntryId, key: K, value: V) -> Option<(EntryId, V)>
+    where
+        K: Clone,
+    {
+        // The value goes in before the key points at it, so readers never see an id without its
+        // value.
+        self.values.insert(id, (key.clone(), value));
+        let old_id = match self.ids.entry(key) {
+            // Evicted before it became visible. Checked while holding the key's entry, so a
+            // concurrent eviction can't unmap the key before we map it.
+            _ if !self.values.contains_key(&id) => return None,
+            Entry::Occupied(mut entry) => entry.insert(id),
+            Entry::Vacant(entry) => {
+                entry.insert(id);
+                return None;
+            }
+        };
+        let (_, (_, old)) = self.values.remove(&old_id)?;
+        Some((old_id, old))
+    }
+
+    /// Like `insert`, but gives the value back if the key already has a live entry.
+    fn insert_vacant(&self, id: EntryId, key: K, value: V) -> Result<(), V>
+    where
+        K: Clone,
+    {
+        match self.ids.entry(key) {
+            Entry::Occupied(entry) if self.values.contains_key(entry.get()) => Err(value),
+            // The old entry is mid-eviction; its removal only unmaps the key if it still
+            // points at the old id.
+            Entry::Occupied(mut entry) => {
+                self.values.insert(id, (entry.key().clone(), value));
+                entry.insert(id);
+                Ok(())
+            }
+            Entry::Vacant(entry) => {
+                self.values.insert(id, (entry.key().clone(), value));
+                entry.insert(id);
+                Ok(())
+            }
+        }
+    }
+
+    fn get<Q>(&self, key: &Q) -> Option<Ref<'_, EntryId, (K, V), IdBuildHasher>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let id = self.ids.get(key)?;
+        self.values.get(&id)
+    }
+
+    /// Looks up every key while read-locking all the shards involved at once.
+    fn consistent_get(&self, keys: &[K]) -> Vec<Option<(EntryId, V)>>
+    where
+        V: Clone,
+    {
+        // Shards are locked ids before values, like writers nest them, and each only once since
+        // the locks aren't reentrant.
+        let id_shards = distinct_shards(keys.iter().map(|key| self.ids.determine_map(key)))
+            .map(|shard| (shard, self.ids.shards()[shard].read()))
+            .collect::<HashMap<_, _>>();
+        let ids = keys
+            .iter()
+            .map(|key| {
+                let shard = &id_shards[&self.ids.determine_map(key)];
+                shard.get(key).map(|id| *id.get())
+            })
+            .collect::<Vec<_>>();
+
+        let value_shards =
+            distinct_shards(ids.iter().flatten().map(|id| self.values.determine_map(id)))
+                .map(|shard| (shard, self.values.shards()[shard].read()))
+                .collect::<HashMap<_, _>>();
+        ids.into_iter()
+            .map(|id| {
+                let id = id?;
+                let shard = &value_shards[&self.values.determine_map(&id)];
+                shard.get(&id).map(|entry| (id, entry.get().1.clone()))
+            })
+            .collect()
+    }
+
+    fn get_id<Q>(&self, key: &Q) -> Option<EntryId>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.ids.get(key).map(|id| *id)
+    }
+
+    fn remove(&self, id: EntryId) -> Option<(K, V)> {
+        shrink_map(&self.values);
+        shrink_map(&self.ids);
+
+        let (_, (key, value)) = self.values.remove(&id)?;
+        // The key may already point at a newer entry.
+        self.ids.remove_if(&key, |_, current| *current == id);
+        Some((key, value))
+    }
+
+    fn try_remove(&self, id: EntryId) -> Result<Option<(K, V)>, Busy> {
+        let shard = &self.values.shards()[self.values.determine_map(&id)];
+        let mut shard = shard.try_write().ok_or(Busy)?;
+        let (key, value) = match shard.remove(&id) {
+            Some(entry) => entry.into_inner(),
+            None => return Ok(None),
+        };
+        drop(shard);
+
+        self.ids.remove_if(&key, |_, current| *current == id);
+        Ok(Some((key, value)))
+    }
+
+    /// Removes the entry for `key`, unmapping the key before the value so readers never see a
+    /// half-removed entry.
+    fn take<Q>(&self, key: &Q) -> Option<(EntryId, K, V)>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        shrink_map(&self.values);
+        shrink_map(&self.ids);
+
+        let (_, id) = self.ids.remove(key)?;
+        let (_, (key, value)) = self.values.remove(&id)?;
+        Some((id, key, value))
+    }
+
+    /// Like `take`, but only if `f` accepts the current value. Checked while holding the key's
+    /// entry, so a concurrent insert can't slip in between.
+    fn take_if<Q>(&self, key: &Q, f: impl FnOnce(&V) -> bool) -> Option<(EntryId, K, V)>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let (_, id) = self.ids.remove_if(key, |_, id| {
+            self.values.get(id).is_some_and(|entry| f(&entry.1))
+        })?;
+        let (_, (key, value)) = self.values.remove(&id)?;
+        Some((id, key, value))
+    }
+}
+
+fn distinct_shards(shards: impl Iterator<Item = usize>) -> impl Iterator<Item = usize> {
+    shards
+        .collect::<std::collections::BTreeSet<_>>()
+        .into_iter()
+}
+
+fn shrink_map<K: Hash + Eq, V, S: BuildHasher + Clone>(map: &DashMap<K, V, S>) {
+    if map.len() < map.capacity() * 4 / 5 {
+        map.shrink_to_fit();
+    }
+}
+
+impl<K, V> Default for EntryMap<K, V>
+where
+    K: Eq + Hash,
+{
+    fn default() -> Self {
+        EntryMap {
+            values: Default::default(),
+            ids: Default::default(),
+            cache_id: NEXT_CACHE_ID.fetch_add(1, Ordering::Relaxed),
+            on_evict: None,
+            name: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::JustStack;
+
+    #[allow(clippy::extra_unused_type_parameters)]
+    fn is_sync<T: Sync>() -> bool {
+        true
+    }
+    #[allow(clippy::extra_unused_type_parameters)]
+    fn is_send<T: Send>() -> bool {
+        true
+    }
+
+    #[test]
+    fn storage_send_sync() {
+        assert!(is_send::<SharedLru>());
+        assert!(is_sync::<SharedLru>());
+    }
+
+    #[test]
+    fn cache_send_sync() {
+        assert!(is_send::<LruCache<(), ()>>());
+        assert!(is_sync::<LruCache<(), ()>>());
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic]
+    fn large_value_warning_panics_in_debug() {
+        let shared = SharedLru::with_byte_limit(1024);
+        let cache = shared.make_cache().with_large_value_warning(0.25);
+        cache.insert(0u8, vec![0u8; 512]);
+    }
+
+    #[test]
+    fn trim_to_evicts_oldest() {
+        let shared = SharedLru::with_byte_limit(4096);
+        let cache = shared.make_cache();
+        cache.insert(0u8, vec![0u8; 1000]);
+        cache.insert(1u8, vec![0u8; 1000]);
+        cache.insert(2u8, vec![0u8; 1000]);
+
+        shared.trim_to(2500);
+
+        assert!(cache.get(&0).is_none());
+        assert!(cache.get(&1).is_some());
+        assert!(cache.get(&2).is_some());
+    }
+
+    #[test]
+    fn trim_to_percent_evicts_to_fraction() {
+        let shared = SharedLru::with_byte_limit(4 * (1 + 8));
+        let cache = shared.make_cache();
+        for i in 0..4u8 {
+            cache.insert(i, 0u64);
+        }
+
+        shared.trim_to_percent(0.5);
+
+        assert_eq!(shared.percent_used(), 0.5);
+        assert!(cache.get(&1).is_none());
+        assert!(cache.get(&2).is_some());
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic]
+    fn trim_to_percent_rejects_nan_in_debug() {
+        SharedLru::with_byte_limit(1024).trim_to_percent(f32::NAN);
+    }
+
+    #[test]
+    #[cfg(not(debug_assertions))]
+    fn trim_to_percent_ignores_nan() {
+        let shared = SharedLru::with_byte_limit(4 * (1 + 8));
+        let cache = shared.make_cache();
+        cache.insert(0u8, 0u64);
+
+        shared.trim_to_percent(f32::NAN);
+        assert!(cache.get(&0).is_some());
+    }
+
+    #[test]
+    fn clear_all_empties_every_cache() {
+        let shared = SharedLru::with_byte_limit(4096);
+        let numbers = shared.make_cache();
+        let words = shared.make_cache();
+        numbers.insert(0u8, 0u64);
+        words.insert("apple", String::from("red"));
+
+        shared.clear_all();
+
+        assert!(numbers.get(&0).is_none());
+        assert!(words.get(&"apple").is_none());
+        assert_eq!(shared.percent_used(), 0.);
+    }
+
+    #[test]
+    fn insert_bounded_limits_eviction() {
+        let shared = SharedLru::with_byte_limit(4096);
+        let cache = shared.make_cache();
+        cache.insert(0u8, vec![0u8; 1000]);
+        cache.insert(1u8, vec![0u8; 1000]);
+        cache.insert(2u8, vec![0u8; 1000]);
+
+        assert!(cache.insert_bounded(3u8, vec![0u8; 3000], 1100).is_err());
+        assert!(cache.get(&0).is_some());
+
+        assert!(cache.insert_bounded(3u8, vec![0u8; 1500], 1100).is_ok());
+        assert!(cache.get(&1).is_none());
+        assert!(cache.get(&2).is_some());
+    }
+
+    #[test]
+    fn insert_bounded_notifies_and_spares_young_entries() {
+        let shared = SharedLru::builder()
+            .byte_limit(3 * (1 + 8))
+            .min_residency(Duration::from_millis(50))
+            .build();
+        let (started, crossed) = (
+            Arc::new(AtomicBool::new(false)),
+            Arc::new(AtomicBool::new(false)),
+        );
+        let flag = Arc::clone(&started);
+        shared.on_eviction_start(move || flag.store(true, Ordering::SeqCst));
+        let flag = Arc::clone(&crossed);
+        shared.on_usage_threshold(0.5, move || flag.store(true, Ordering::SeqCst));
+        let cache = shared.make_cache();
+
+        assert!(cache.insert_bounded(0u8, 0u64, 0).is_ok());
+        assert!(cache.insert_bounded(1u8, 0u64, 0).is_ok());
+        assert!(crossed.load(Ordering::SeqCst));
+
+        std::thread::sleep(Duration::from_millis(60));
+        cache.insert(2u8, 0u64);
+        cache.touch_many(&[0, 1]);
+        // 2 is the least recently used, but still young.
+        assert!(cache.insert_bounded(3u8, 0u64, 1 + 8).is_ok());
+        assert!(started.load(Ordering::SeqCst));
+        assert!(cache.get(&0).is_none());
+        assert!(cache.get(&2).is_some());
+    }
+
+    struct InsertOnDrop(Weak<LruCache<u8, InsertOnDrop>>);
+
+    impl JustStack for InsertOnDrop {}
+
+    impl Drop for InsertOnDrop {
+        fn drop(&mut self) {
+            if let Some(cache) = self.0.upgrade() {
+                cache.insert(2, InsertOnDrop(Weak::new()));
+            }
+        }
+    }
+
+    #[test]
+    fn evicted_value_drop_can_use_cache() {
+        let shared = SharedLru::with_byte_limit(3 * std::mem::size_of::<(u8, InsertOnDrop)>());
+        let cache = Arc::new(shared.make_cache());
+        cache.insert(0, InsertOnDrop(Arc::downgrade(&cache)));
+        cache.insert(1, InsertOnDrop(Weak::new()));
+
+        shared.clear_all();
+
+        assert!(cache.get(&2).is_some());
+    }
+
+    #[test]
+    fn builder_max_entries() {
+        let shared = SharedLru::builder().max_entries(2).build();
+        let cache = shared.make_cache();
+        cache.insert(0u8, 0u8);
+        cache.insert(1u8, 1u8);
+        cache.insert(2u8, 2u8);
+
+        assert!(cache.get(&0).is_none());
+        assert!(cache.get(&1).is_some());
+        assert!(cache.get(&2).is_some());
+    }
+
+    #[test]
+    fn pop_frees_bytes() {
+        let shared = SharedLru::with_byte_limit(4096);
+        let cache = shared.make_cache();
+        cache.insert(0u8, vec![0u8; 1000]);
+
+        assert_eq!(cache.pop(&0).map(|v| v.len()), Some(1000));
+        assert!(cache.get(&0).is_none());
+        assert_eq!(shared.percent_used(), 0.);
+    }
+
+    #[test]
+    fn wait_for_eviction_wakes_when_pool_fills() {
+        let shared = SharedLru::with_byte_limit(2048);
+        let waiter = {
+            let shared = Arc::clone(&shared);
+            std::thread::spawn(move || shared.wait_for_eviction_timeout(Duration::from_secs(10)))
+        };
+
+        let cache = shared.make_cache();
+        for i in 0usize.. {
+            if waiter.is_finished() {
+                break;
+            }
+            cache.insert((i % 4) as u8, vec![0u8; 1000]);
+        }
+
+        assert!(waiter.join().unwrap());
+    }
+
+    #[test]
+    fn snapshot_orders_by_recency() {
+        let shared = SharedLru::with_byte_limit(4096);
+        let cache = shared.make_cache();
+        cache.insert(0u8, Vec::new());
+        cache.insert(1u8, vec![0u8; 100]);
+        cache.get(&0);
+
+        let snapshot = shared.snapshot_metadata();
+        let sizes = snapshot.entries.iter().map(|e| e.bytes).collect::<Vec<_>>();
+        let vec_size = std::mem::size_of::<Vec<u8>>();
+        assert_eq!(sizes, vec![1 + vec_size + 100, 1 + vec_size]);
+        assert_eq!(snapshot.entries[1].recency_rank, 1);
+    }
+
+    #[test]
+    fn skips_entries_held_by_readers() {
+        let shared = SharedLru::builder()
+            .byte_limit(3000)
+            .skip_busy_entries(true)
+            .build();
+        let held = shared.make_cache();
+        let other = shared.make_cache();
+        held.insert(0u8, vec![0u8; 1000]);
+        other.insert(1u8, vec![0u8; 1000]);
+
+        let guard = held.get(&0).unwrap();
+        // Make the held entry the oldest again.
+        other.get(&1);
+        other.insert(2u8, vec![0u8; 1000]);
+        drop(guard);
+
+        assert!(held.get(&0).is_some());
+        assert!(other.get(&1).is_none());
+    }
+
+    #[test]
+    fn counts_ghost_hits() {
+        let shared = SharedLru::builder()
+            .byte_limit(2100)
+            .ghost_capacity(16)
+            .build();
+        let cache = shared.make_cache();
+        cache.insert(0u8, vec![0u8; 1000]);
+        cache.insert(1u8, vec![0u8; 1000]);
+        cache.insert(2u8, vec![0u8; 1000]);
+
+        assert!(cache.get(&0).is_none());
+        assert!(cache.get(&3).is_none());
+        assert_eq!(shared.ghost_hits(), 1);
+    }
+
+    #[test]
+    fn ghosts_count_once_and_are_forgotten_on_reinsert() {
+        let shared = SharedLru::builder()
+            .byte_limit(2100)
+            .ghost_capacity(16)
+            .build();
+        let cache = shared.make_cache();
+        for i in 0..4u8 {
+            cache.insert(i, vec![0u8; 1000]);
+        }
+
+        assert_eq!(cache.get_classified(&0).1, MissKind::Capacity);
+        assert_eq!(cache.get_classified(&0).1, MissKind::Compulsory);
+
+        cache.insert(1, vec![0u8; 1000]);
+        cache.pop(&1);
+        assert_eq!(cache.get_classified(&1).1, MissKind::Compulsory);
+    }
+
+    #[test]
+    fn ghosts_do_not_outlive_their_cache() {
+        let shared = SharedLru::builder()
+            .byte_limit(2100)
+            .ghost_capacity(16)
+            .build();
+        let cache = shared.make_cache();
+        for i in 0..3u8 {
+            cache.insert(i, vec![0u8; 1000]);
+        }
+        drop(cache);
+
+        let cache = shared.make_cache::<u8, Vec<u8>>();
+        assert_eq!(cache.get_classified(&0).1, MissKind::Compulsory);
+    }
+
+    #[test]
+    fn insert_full_reports_outcome() {
+        let shared = SharedLru::with_byte_limit(100);
+        let cache = shared.make_cache();
+
+        assert_eq!(cache.insert_full(0u8, vec![1u8]), Inserted::New);
+        assert_eq!(
+            cache.insert_full(0u8, vec![2u8]),
+            Inserted::Replaced(vec![1u8])
+        );
+        assert_eq!(
+            shared.inner.lock().unwrap().allocator.used(),
+            1 + vec![2u8].bytes()
+        );
+        assert_eq!(
+            cache.insert_full(1u8, vec![0u8; 200]),
+            Inserted::TooLarge(vec![0u8; 200])
+        );
+    }
+
+    #[test]
+    fn maintain_evicts_rejected_entries() {
+        let shared = SharedLru::with_byte_limit(10_000);
+        let cache = shared.make_cache();
+        cache.insert(0u8, vec![0u8; 1000]);
+        cache.insert(1u8, vec![0u8; 10]);
+        cache.insert(2u8, vec![0u8; 1000]);
+
+        let mut seen = Vec::new();
+        cache.maintain(|key, _, meta| {
+            seen.push((*key, meta.recency_rank));
+            if meta.bytes > 500 && meta.recency_rank == 0 {
+                Keep::No
+            } else {
+                Keep::Yes
+            }
+        });
+        seen.sort_unstable();
+
+        assert_eq!(seen, vec![(0, 0), (1, 1), (2, 2)]);
+        assert!(cache.get(&0).is_none());
+        assert!(cache.get(&1).is_some());
+        assert!(cache.get(&2).is_some());
+    }
+
+    #[test]
+    fn rejects_unreasonable_sizes() {
+        let shared = SharedLru::builder().max_reasonable_entry_bytes(100).build();
+        let cache = shared.make_cache();
+
+        assert_eq!(
+            cache.insert_full(0u8, vec![0u8; 200]),
+            Inserted::TooLarge(vec![0u8; 200])
+        );
+        assert_eq!(cache.insert_full(1u8, vec![0u8; 10]), Inserted::New);
+    }
+
+    #[test]
+    fn frozen_entries_are_not_evicted() {
+        let shared = SharedLru::with_byte_limit(3000);
+        let frozen = shared.make_cache();
+        frozen.insert(0u8, vec![0u8; 1000]);
+        frozen.freeze();
+
+        let other = shared.make_cache();
+        for i in 0..10u8 {
+            other.insert(i, vec![0u8; 1000]);
+        }
+        assert!(frozen.get(&0).is_some());
+        assert!(other.get(&9).is_some());
+        assert_eq!(
+            other.insert_full(10, vec![0u8; 2500]),
+            Inserted::TooLarge(vec![0u8; 2500])
+        );
+    }
+
+    #[test]
+    fn insert_into_frozen_is_refused() {
+        let shared = SharedLru::with_byte_limit(1024);
+        let cache = shared.make_cache();
+        cache.insert(0u8, 0u8);
+        cache.freeze();
+
+        assert_eq!(cache.insert_full(1, 1), Inserted::Frozen(1));
+        assert_eq!(cache.replace(&0, 1), None);
+        assert_eq!(cache.get(&0).as_deref(), Some(&0));
+        assert!(cache.get(&1).is_none());
+    }
+
+    #[test]
+    fn tiny_lfu_protects_popular_entries() {
+        let shared = SharedLru::builder()
+            .byte_limit(2100)
+            .policy(Policy::TinyLfu)
+            .build();
+        let cache = shared.make_cache();
+        cache.insert(0u8, vec![0u8; 1000]);
+        cache.insert(1u8, vec![0u8; 1000]);
+        for _ in 0..3 {
+            cache.get(&0);
+            cache.get(&1);
+        }
+
+        assert_eq!(
+            cache.insert_full(2, vec![0u8; 1000]),
+            Inserted::Rejected(vec![0u8; 1000])
+        );
+        assert!(cache.get(&0).is_some());
+
+        for _ in 0..10 {
+            cache.get(&3);
+        }
+        assert_eq!(cache.insert_full(3, vec![0u8; 1000]), Inserted::New);
+    }
+
+    #[test]
+    fn recency_rank_counts_from_oldest() {
+        let shared = SharedLru::with_byte_limit(1000);
+        let cache = shared.make_cache();
+        cache.insert(0u8, 0u8);
+        cache.insert(1u8, 0u8);
+        cache.get(&0);
+
+        assert_eq!(cache.recency_rank(&1), Some(0));
+        assert_eq!(cache.recency_rank(&0), Some(1));
+        assert_eq!(cache.recency_rank(&2), None);
+    }
+
+    #[test]
+    fn concurrent_inserts_keep_accounting_consistent() {
+        let shared = SharedLru::with_byte_limit(20_000);
+        let cache = Arc::new(shared.make_cache());
+
+        let threads = (0..4u32)
+            .map(|t| {
+                let cache = Arc::clone(&cache);
+                std::thread::spawn(move || {
+                    for i in 0..2_000u32 {
+                        cache.insert((t, i % 50), vec![0u8; 1000]);
+                        if let Some(value) = cache.get(&(t, i % 50)) {
+                            assert_eq!(value.len(), 1000);
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let inner = shared.inner.lock().unwrap();
+        let stored = cache.entry_map.values.iter().collect::<Vec<_>>();
+        for entry in &stored {
+            assert!(inner.allocator.contains(*entry.key()));
+        }
+        assert_eq!(stored.len(), inner.allocator.oldest_first().count());
+        assert_eq!(stored.len(), cache.entry_map.ids.len());
+    }
+
+    #[test]
+    fn random_operations_keep_invariants() {
+        use rand::{rngs::SmallRng, Rng, SeedableRng};
+
+        let mut rng = SmallRng::seed_from_u64(0);
+        let shared = SharedLru::with_byte_limit_seeded(2_000, 0);
+        let mut caches = [
+            shared.make_cache(),
+            shared.make_cache(),
+            shared.make_cache_with_quota(500),
+        ];
+
+        for _ in 0..5_000 {
+            let cache = rng.gen_range(0..caches.len());
+            let key = rng.gen_range(0..20u8);
+            match rng.gen_range(0..10) {
+                0..=3 => caches[cache].insert(key, vec![0u8; rng.gen_range(0..300)]),
+                4 => drop(caches[cache].get(&key)),
+                5 => drop(caches[cache].pop(&key)),
+                6 => drop(caches[cache].replace(&key, vec![0u8; rng.gen_range(0..300)])),
+                7 => drop(caches[cache].remove_many(&[key, key / 2])),
+                8 => caches[cache] = shared.make_cache(),
+                _ => shared.trim_to(rng.gen_range(0..2_000)),
+            }
+            shared.debug_invariants();
+        }
+    }
+
+    #[test]
+    fn touch_many_refreshes_keys() {
+        let shared = SharedLru::with_byte_limit(1000);
+        let cache = shared.make_cache();
+        for i in 0..4u8 {
+            cache.insert(i, 0u8);
+        }
+        cache.touch_many(&[1, 0, 9]);
+
+        assert_eq!(cache.recency_rank(&2), Some(0));
+        assert_eq!(cache.recency_rank(&1), Some(2));
+        assert_eq!(cache.recency_rank(&0), Some(3));
+    }
+
+    #[test]
+    fn same_seed_gives_same_pool() {
+        let ids = || {
+            let shared = SharedLru::with_byte_limit_seeded(1000, 7);
+            let cache = shared.make_cache();
+            for i in 0..10u8 {
+                cache.insert(i, i);
+            }
+            let snapshot = shared.snapshot_metadata();
+            snapshot.entries.iter().map(|e| e.id).collect::<Vec<_>>()
+        };
+        assert_eq!(ids(), ids());
+    }
+
+    #[test]
+    fn get_project_extracts_part() {
+        let shared = SharedLru::with_byte_limit(1000);
+        let cache = shared.make_cache();
+        cache.insert(0u8, vec![1u8, 2, 3]);
+
+        assert_eq!(cache.get_project(&0, |v| v.len()), Some(3));
+        assert_eq!(cache.get_project(&1, |v| v.len()), None);
+    }
+
+    #[test]
+    fn get_or_insert_sized_skips_hopeless_values() {
+        let shared = SharedLru::with_byte_limit(100);
+        let cache = shared.make_cache();
+
+        let mut ran = false;
+        let value = cache.get_or_insert_sized(0u8, 1000, || {
+            ran = true;
+            vec![0u8; 1000]
+        });
+        assert!(value.is_none());
+        assert!(!ran);
+
+        let value = cache.get_or_insert_sized(0u8, 10, || vec![0u8; 10]);
+        assert_eq!(value.map(|v| v.len()), Some(10));
+    }
+
+    #[test]
+    fn pinned_entry_survives_until_guard_drops() {
+        let shared = SharedLru::with_byte_limit(2 * (1 + 8));
+        let cache = shared.make_cache();
+        cache.insert(0u8, 0u64);
+        cache.insert(1u8, 1u64);
+
+        let guard = cache.pin_temporarily(&0).unwrap();
+        cache.insert(2u8, 2u64);
+        assert!(cache.get_untouched(&0).is_some());
+        assert!(cache.get_untouched(&1).is_none());
+
+        drop(guard);
+        cache.insert(3u8, 3u64);
+        assert!(cache.get_untouched(&0).is_none());
+    }
+
+    #[test]
+    fn into_hashmap_releases_bytes() {
+        let shared = SharedLru::with_byte_limit(1000);
+        let cache = shared.make_cache();
+        cache.insert(0u8, 10u64);
+        cache.insert(1u8, 11u64);
+
+        let map = cache.into_hashmap();
+        assert_eq!(map, HashMap::from([(0, 10), (1, 11)]));
+        assert_eq!(shared.percent_used(), 0.);
+    }
+
+    #[test]
+    fn measure_changes_charged_bytes() {
+        let shared = SharedLru::with_byte_limit(10_000);
+
+        let shallow = shared.make_cache().with_measure(Measure::Shallow);
+        shallow.insert(0u8, vec![0u8; 1000]);
+        assert_eq!(
+            shallow.get_with_size(&0).map(|(_, bytes)| bytes),
+            Some(1 + std::mem::size_of::<Vec<u8>>())
+        );
+
+        let custom = shared
+            .make_cache()
+            .with_measure(Measure::Custom(Arc::new(|_, v: &Vec<u8>| v.len() / 10)));
+        assert_eq!(custom.measure(&0, &vec![0u8; 1000]), 100);
+        custom.insert(0u8, vec![0u8; 1000]);
+        assert_eq!(custom.get_with_size(&0).map(|(_, bytes)| bytes), Some(100));
+
+        let sampled = shared.make_cache().with_measure(Measure::Sampled {
+            threshold: 10,
+            sample_size: 4,
+        });
+        let strings = vec![String::from("ab"); 100];
+        assert_eq!(sampled.measure(&0u8, &strings), 1 + strings.bytes());
+    }
+
+    #[test]
+    fn merge_from_interleaves_and_keeps_newest() {
+        let shared = SharedLru::with_byte_limit(10_000);
+        let a = shared.make_cache();
+        let b = shared.make_cache();
+        a.insert(0u8, 0u64);
+        b.insert(1u8, 10u64);
+        b.insert(0u8, 20u64);
+        a.insert(2u8, 2u64);
+
+        a.merge_from(b);
+
+        assert_eq!(a.get_untouched(&0).as_deref(), Some(&20));
+        assert_eq!(a.get_untouched(&1).as_deref(), Some(&10));
+        assert!(a.recency_rank(&1) < a.recency_rank(&2));
+        assert_eq!(shared.cache_count(), 1);
+    }
+
+    #[test]
+    fn shared_value_outlives_eviction() {
+        let shared = SharedLru::with_byte_limit(1000);
+        let cache = shared.make_shared_value_cache();
+        cache.insert(0u8, Arc::new(vec![1u8, 2, 3]));
+
+        let value = cache.get_arc(&0).unwrap();
+        shared.clear_all();
+        assert_eq!(*value, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn owner_of_names_cache() {
+        let shared = SharedLru::with_byte_limit(1000);
+        let named = shared.make_named_cache("users");
+        let unnamed = shared.make_cache();
+        named.insert(0u8, 0u64);
+        unnamed.insert(0u8, 0u64);
+
+        let snapshot = shared.snapshot_metadata();
+        let mut owners = snapshot
+            .entries
+            .iter()
+            .map(|entry| shared.owner_of(entry.id))
+            .collect::<Vec<_>>();
+        owners.sort();
+        assert_eq!(owners, [None, Some(String::from("users"))]);
+    }
+
+    #[test]
+    fn snapshot_names_owning_caches() {
+        let shared = SharedLru::with_byte_limit(1000);
+        let named = shared.make_named_cache("users");
+        let unnamed = shared.make_cache();
+        named.insert(0u8, 0u64);
+        unnamed.insert(0u8, 0u64);
+
+        let caches = shared
+            .snapshot_metadata()
+            .entries
+            .into_iter()
+            .map(|entry| entry.cache)
+            .collect::<Vec<_>>();
+        assert_eq!(caches, [Some(String::from("users")), None]);
+    }
+
+    #[test]
+    fn dropped_cache_releases_bytes() {
+        let shared = SharedLru::with_byte_limit(1000);
+        let cache = shared.make_cache();
+        cache.insert(0u8, 0u64);
+        assert!(shared.percent_used() > 0.);
+
+        drop(cache);
+        assert_eq!(shared.percent_used(), 0.);
+    }
+
+    #[test]
+    fn fork_subset_copies_selected_entries() {
+        let shared = SharedLru::with_byte_limit(1000);
+        let cache = shared.make_cache();
+        for i in 0..4u8 {
+            cache.insert(i, u64::from(i));
+        }
+
+        let (forked_pool, forked) = cache.fork_subset(&[1, 3, 5]);
+        assert_eq!(forked_pool.byte_limit(), 2 * (1 + 8));
+        assert_eq!(forked.get(&1).as_deref(), Some(&1));
+        assert_eq!(forked.get(&3).as_deref(), Some(&3));
+        assert!(forked.get(&0).is_none());
+        assert_eq!(cache.recency_rank(&0), Some(0));
+    }
+
+    #[test]
+    fn fork_subset_keeps_measure_and_options() {
+        let shared = SharedLru::builder()
+            .byte_limit(1000)
+            .ghost_capacity(10)
+            .build();
+        let cache = shared
+            .make_cache()
+            .with_measure(Measure::Custom(Arc::new(|_, v: &u64| *v as usize)));
+        cache.insert(0u8, 5u64);
+        cache.insert(1u8, 7u64);
+
+        let (forked_pool, forked) = cache.fork_subset(&[0, 1]);
+        assert_eq!(forked_pool.byte_limit(), 5 + 7);
+        forked.insert(2, 6);
+        assert_eq!(forked.get_classified(&0).1, MissKind::Capacity);
+    }
+
+    #[test]
+    fn reset_forgets_entries_and_ghosts() {
+        let shared = SharedLru::builder()
+            .byte_limit(2 * (1 + 8))
+            .ghost_capacity(10)
+            .build();
+        let cache = shared.make_cache();
+        for i in 0..3u8 {
+            cache.insert(i, 0u64);
+        }
+        assert!(cache.get(&0).is_none());
+        assert_eq!(shared.ghost_hits(), 1);
+
+        shared.reset();
+
+        assert!(cache.get(&1).is_none());
+        assert_eq!(shared.ghost_hits(), 0);
+        assert_eq!(shared.percent_used(), 0.);
+        cache.insert(0, 0);
+        assert!(cache.get(&0).is_some());
+    }
+
+    #[test]
+    fn get_or_insert_keep_existing_keeps_racing_value() {
+        let shared = SharedLru::with_byte_limit(1000);
+        let cache = shared.make_cache();
+
+        let value = cache.get_or_insert_keep_existing(0u8, || {
+            cache.insert(0u8, 1u64);
+            2u64
+        });
+        assert_eq!(value.as_deref(), Some(&1));
+        drop(value);
+
+        let (_, bytes) = cache.get_with_size(&0).unwrap();
+        assert_eq!(shared.snapshot_metadata().entries.len(), 1);
+        assert_eq!(bytes, 1 + 8);
+    }
+
+    #[test]
+    fn eviction_events_report_evictions() {
+        let shared = SharedLru::with_byte_limit(2 * (1 + 8));
+        let events = shared.eviction_events();
+        let cache = shared.make_named_cache("numbers");
+        for i in 0..3u8 {
+            cache.insert(i, 0u64);
+        }
+
+        let event = events.try_recv().unwrap();
+        assert_eq!(event.cache.as_deref(), Some("numbers"));
+        assert_eq!(event.bytes, 1 + 8);
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn consistent_get_sees_one_state() {
+        let shared = SharedLru::with_byte_limit(usize::MAX);
+        let cache = Arc::new(shared.make_cache());
+        cache.insert(0u8, 0u64);
+        cache.insert(1u8, 0u64);
+
+        // Key 0 is always written first, so in any single state it's equal or one ahead.
+        let writer = {
+            let cache = Arc::clone(&cache);
+            std::thread::spawn(move || {
+                for n in 1..2000u64 {
+                    cache.insert(0, n);
+                    cache.insert(1, n);
+                }
+            })
+        };
+        for _ in 0..2000 {
+            let values = cache.consistent_get(&[0, 1]);
+            let (first, second) = (values[0].unwrap(), values[1].unwrap());
+            assert!(
+                first == second || first == second + 1,
+                "{} {}",
+                first,
+                second
+            );
+        }
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn get_or_insert_with_headroom_reports_free_bytes() {
+        let shared = SharedLru::with_byte_limit(100);
+        let cache = shared.make_cache();
+
+        let (value, headroom) = cache.get_or_insert_with_headroom(0u8, || 1u64);
+        assert_eq!(value.as_deref(), Some(&1));
+        assert_eq!(headroom, 100 - (1 + 8));
+        drop(value);
+
+        let (value, headroom) = cache.get_or_insert_with_headroom(0u8, || unreachable!());
+        assert_eq!(value.as_deref(), Some(&1));
+        assert_eq!(headroom, 100 - (1 + 8));
+    }
+
+    #[test]
+    fn get_or_insert_with_headroom_looks_up_like_get() {
+        let shared = SharedLru::builder()
+            .byte_limit(2 * (1 + 8))
+            .ghost_capacity(10)
+            .build();
+        let cache = shared.make_cache().with_touch_probability(0.);
+        for i in 0..3u8 {
+            cache.insert(i, 0u64);
+        }
+
+        drop(cache.get_or_insert_with_headroom(0u8, || 0u64));
+        assert_eq!(shared.ghost_hits(), 1);
+
+        // An untouched hit on 2 leaves it older than 0.
+        drop(cache.get_or_insert_with_headroom(2u8, || unreachable!()));
+        cache.insert(3, 0);
+        assert!(cache.get(&2).is_none());
+        assert!(cache.get(&0).is_some());
+    }
+
+    #[test]
+    fn grouped_entries_evict_together() {
+        let shared = SharedLru::with_byte_limit(4 * (1 + 8));
+        let cache = shared.make_cache();
+        cache.insert_grouped("image", 0u8, 0u64);
+        cache.insert_grouped("image", 1u8, 0u64);
+        cache.insert(2u8, 0u64);
+        cache.insert(3u8, 0u64);
+
+        assert!(cache.get(&1).is_some());
+        cache.insert(4u8, 0u64);
+        assert!(cache.get(&0).is_some());
+        assert!(cache.get(&2).is_none());
+
+        for i in 5..8u8 {
+            cache.insert(i, 0u64);
+        }
+        assert!(cache.get(&0).is_none());
+        assert!(cache.get(&1).is_none());
+        assert_eq!(cache.entry_map.values.len(), 3);
+        shared.debug_invariants();
+    }
+
+    #[test]
+    fn insert_bounded_counts_quota_evictions() {
+        let shared = SharedLru::with_byte_limit(1000);
+        let cache = shared.make_cache_with_quota(3 * (1 + 8));
+        for i in 0..3u8 {
+            cache.insert_grouped("tiles", i, 0u64);
+        }
+
+        assert!(cache.insert_bounded(3u8, 0u64, 1 + 8).is_err());
+        assert!(cache.get(&0).is_some());
+        assert!(cache.insert_bounded(3u8, 0u64, 3 * (1 + 8)).is_ok());
+        assert!(cache.get(&0).is_none());
+        assert!(cache.get(&3).is_some());
+    }
+
+    #[test]
+    fn insert_bounded_counts_group_mates() {
+        let shared = SharedLru::with_byte_limit(3 * (1 + 8));
+        let cache = shared.make_cache();
+        for i in 0..3u8 {
+            cache.insert_grouped("tiles", i, 0u64);
+        }
+
+        assert!(cache.insert_bounded(3u8, 0u64, 1 + 8).is_err());
+        assert!(cache.get(&0).is_some());
+        assert!(cache.insert_bounded(3u8, 0u64, 3 * (1 + 8)).is_ok());
+        assert!(cache.get(&0).is_none());
+        assert!(cache.get(&2).is_none());
+    }
+
+    #[test]
+    fn remeasure_reports_eviction_through_group_mates() {
+        let entry = size_of::<u8>() + Mutex::new(Vec::<u8>::new()).bytes();
+        let shared = SharedLru::with_byte_limit(2 * entry + 4);
+        let cache = shared.make_cache();
+        cache.insert_grouped("tiles", 0u8, Mutex::new(Vec::<u8>::new()));
+        cache.insert_grouped("tiles", 1u8, Mutex::new(Vec::new()));
+
+        cache.get(&1).unwrap().lock().unwrap().reserve_exact(8);
+        assert!(!cache.remeasure(&1));
+        assert!(cache.get(&0).is_none());
+        assert!(cache.get(&1).is_none());
+    }
+
+    #[test]
+    fn recent_evictions_keeps_the_newest() {
+        let shared = SharedLru::builder()
+            .byte_limit(1 + 8)
+            .recent_evictions(2)
+            .build();
+        let cache = shared.make_named_cache("numbers");
+        for i in 0..4u8 {
+            cache.insert(i, 0u64);
+        }
+
+        let recent = shared.recent_evictions();
+        assert_eq!(recent.len(), 2);
+        assert!(recent.iter().all(|r| r.cache.as_deref() == Some("numbers")));
+        assert!(recent[0].at <= recent[1].at);
+        let hashes = [1u8, 2].map(|k| cache.entry_map.key_hash(&k));
+        assert_eq!(
+            recent.iter().map(|r| r.key_hash).collect::<Vec<_>>(),
+            hashes
+        );
+    }
+
+    #[test]
+    fn remove_many_counts_present_keys() {
+        let shared = SharedLru::with_byte_limit(1000);
+        let cache = shared.make_cache();
+        cache.insert(0u8, 0u64);
+        cache.insert(1u8, 1u64);
+
+        assert_eq!(cache.remove_many(&[0, 1, 2]), 2);
+        assert_eq!(shared.percent_used(), 0.);
+    }
+
+    #[test]
+    fn get_classified_tells_misses_apart() {
+        let shared = SharedLru::builder()
+            .byte_limit(2 * (1 + 8))
+            .ghost_capacity(10)
+            .build();
+        let cache = shared.make_cache();
+        for i in 0..3u8 {
+            cache.insert(i, 0u64);
+        }
+
+        assert_eq!(cache.get_classified(&0).1, MissKind::Capacity);
+        assert_eq!(cache.get_classified(&1).1, MissKind::Hit);
+        assert_eq!(cache.get_classified(&9).1, MissKind::Compulsory);
+    }
+
+    #[test]
+    fn replace_only_updates_present_keys() {
+        let shared = SharedLru::with_byte_limit(1000);
+        let cache = shared.make_cache();
+        cache.insert(0u8, vec![0u8; 10]);
+
+        assert_eq!(cache.replace(&0, vec![1u8; 20]), Some(vec![0u8; 10]));
+        assert_eq!(
+            cache.get_with_size(&0).map(|(_, bytes)| bytes),
+            Some(1 + 24 + 20)
+        );
+        assert_eq!(cache.replace(&1, vec![]), None);
+        assert!(cache.get(&1).is_none());
+    }
+
+    #[test]
+    fn limits_by_custom_cost() {
+        struct Handles(usize);
+
+        impl MemorySize for Handles {
+            fn bytes(&self) -> usize {
+                std::mem::size_of::<Self>()
+            }
+        }
+
+        let shared = SharedLru::with_cost_limit(4);
+        let cache = shared
+            .make_cache()
+            .with_measure(Measure::Custom(Arc::new(|_, handles: &Handles| handles.0)));
+        cache.insert(0u8, Handles(3));
+        cache.insert(1u8, Handles(1));
+        cache.insert(2u8, Handles(2));
+
+        assert!(cache.get(&0).is_none());
+        assert!(cache.get(&1).is_some());
+        assert!(cache.get(&2).is_some());
+    }
+
+    #[test]
+    fn get_or_insert_if_skips_rejected_values() {
+        let shared = SharedLru::with_byte_limit(1000);
+        let cache = shared.make_cache();
+
+        let value = cache.get_or_insert_if(0u8, Vec::<u8>::new, |v| !v.is_empty());
+        assert!(matches!(value, Some(Fetched::Uncached(_))));
+        assert!(cache.get(&0).is_none());
+
+        let value = cache.get_or_insert_if(0u8, || vec![1u8], |v| !v.is_empty());
+        assert_eq!(value.as_deref(), Some(&vec![1u8]));
+        drop(value);
+        assert!(cache.get(&0).is_some());
+    }
+
+    #[test]
+    fn insert_presized_charges_given_size() {
+        let shared = SharedLru::with_byte_limit(1000);
+        let cache = shared.make_cache();
+        cache.insert_presized(0u8, vec![0u8; 100], 50);
+
+        assert_eq!(
+            cache.get_with_size(&0).map(|(_, bytes)| bytes),
+            Some(1 + 50)
+        );
+    }
+
+    #[test]
+    fn quota_evicts_own_entries_first() {
+        let shared = SharedLru::with_byte_limit(10_000);
+        let other = shared.make_cache();
+        other.insert(0u8, vec![0u8; 1000]);
+
+        let limited = shared.make_cache_with_quota(2100);
+        limited.insert(0u8, vec![0u8; 1000]);
+        limited.insert(1u8, vec![0u8; 1000]);
+        limited.insert(2u8, vec![0u8; 1000]);
+
+        assert!(other.get(&0).is_some());
+        assert!(limited.get(&0).is_none());
+        assert!(limited.get(&1).is_some());
+        assert!(limited.get(&2).is_some());
+        assert_eq!(
+            limited.insert_full(3, vec![0u8; 3000]),
+            Inserted::TooLarge(vec![0u8; 3000])
+        );
+    }
+
+    #[test]
+    fn update_many_remeasures() {
+        let shared = SharedLru::with_byte_limit(10_000);
+        let cache = shared.make_cache();
+        cache.insert(0u8, vec![0u8; 10]);
+        cache.insert(1u8, vec![0u8; 10]);
+
+        cache.update_many(&[0, 1, 2], |_, value| value.resize(1000, 0));
+
+        assert_eq!(
+            cache.get_with_size(&0).unwrap().1,
+            1 + vec![0u8; 1000].bytes()
+        );
+        assert_eq!(cache.get(&1).unwrap().len(), 1000);
+    }
+
+    #[test]
+    fn warm_from_keeps_hottest() {
+        let shared = SharedLru::with_byte_limit(2100);
+        let cache = shared.make_cache();
+        let entries = || {
+            vec![
+                (0u8, vec![0u8; 1000]),
+                (1, vec![0u8; 1500]),
+                (2, vec![0u8; 10]),
+            ]
+        };
+
+        assert_eq!(cache.warm_from(entries(), WarmStrategy::StopWhenFull), 1);
+        assert!(cache.get(&2).is_none());
+
+        cache.pop(&0);
+        assert_eq!(cache.warm_from(entries(), WarmStrategy::NewestFirst), 2);
+        assert_eq!(cache.recency_rank(&2), Some(0));
+        assert_eq!(cache.recency_rank(&0), Some(1));
+    }
+
+    #[test]
+    fn evict_id_from_snapshot() {
+        let shared = SharedLru::with_byte_limit(1000);
+        let cache = shared.make_cache();
+        cache.insert(0u8, 0u8);
+        cache.insert(1u8, 0u8);
+
+        let oldest = shared.snapshot_metadata().entries[0].id;
+        assert!(shared.evict_id(oldest));
+        assert!(!shared.evict_id(oldest));
+        assert!(cache.get(&0).is_none());
+        assert!(cache.get(&1).is_some());
+    }
+
+    #[test]
+    fn insert_evicting_reports_pressure() {
+        let shared = SharedLru::with_byte_limit(2100);
+        let cache = shared.make_cache();
+
+        assert!(!cache.insert_evicting(0u8, vec![0u8; 1000]));
+        assert!(!cache.insert_evicting(1u8, vec![0u8; 1000]));
+        assert!(cache.insert_evicting(2u8, vec![0u8; 1000]));
+    }
+
+    #[test]
+    fn counts_live_caches() {
+        let shared = SharedLru::with_byte_limit(1000);
+        let a = shared.make_cache::<u8, u8>();
+        let b = shared.make_cache::<u8, u8>();
+        assert_eq!(shared.cache_count(), 2);
+
+        drop(a);
+        assert_eq!(shared.cache_count(), 1);
+        drop(b);
+    }
+
+    struct Growable(Mutex<Vec<u8>>);
+
+    impl MemorySize for Growable {
+        fn bytes(&self) -> usize {
+            self.0.lock().unwrap().bytes()
+        }
+    }
+
+    #[test]
+    fn remeasure_evicts_after_growth() {
+        let shared = SharedLru::with_byte_limit(3000);
+        let cache = shared.make_cache();
+        cache.insert(0u8, Growable(Mutex::new(vec![0u8; 1000])));
+        cache.insert(1u8, Growable(Mutex::new(vec![0u8; 1000])));
+
+        cache.get(&1).unwrap().0.lock().unwrap().extend([0u8; 1500]);
+        assert!(cache.remeasure(&1));
+        assert!(cache.get(&0).is_none());
+
+        cache.get(&1).unwrap().0.lock().unwrap().extend([0u8; 5000]);
+        assert!(!cache.remeasure(&1));
+        assert!(cache.get(&1).is_none());
+        assert_eq!(shared.percent_used(), 0.);
+    }
+
+    #[test]
+    fn get_with_borrowed_key() {
+        let shared = SharedLru::with_byte_limit(1024);
+        let cache = shared.make_cache();
+        cache.insert(String::from("apple"), 1u8);
+
+        assert_eq!(cache.get("apple").map(|v| *v), Some(1));
+    }
+}
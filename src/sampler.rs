@@ -0,0 +1,56 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Decides whether a `get` should update recency, without taking any lock.
+pub(crate) struct TouchSampler {
+    threshold: u64,
+    state: AtomicU64,
+}
+
+const GOLDEN_GAMMA: u64 = 0x9e37_79b9_7f4a_7c15;
+
+impl TouchSampler {
+    pub(crate) fn new(seed: u64) -> Self {
+        TouchSampler {
+            threshold: u64::MAX,
+            state: AtomicU64::new(seed),
+        }
+    }
+
+    pub(crate) fn set_probability(&mut self, probability: f64) {
+        self.threshold = if probability >= 1. {
+            u64::MAX
+        } else {
+            (probability.max(0.) * u64::MAX as f64) as u64
+        };
+    }
+
+    pub(crate) fn sample(&self) -> bool {
+        if self.threshold == u64::MAX {
+            return true;
+        }
+
+        // SplitMix64 over a shared counter.
+        let mut z = self
+            .state
+            .fetch_add(GOLDEN_GAMMA, Ordering::Relaxed)
+            .wrapping_add(GOLDEN_GAMMA);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        z < self.threshold
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn samples_roughly_the_requested_fraction() {
+        let mut sampler = TouchSampler::new(0);
+        sampler.set_probability(0.1);
+
+        let hits = (0..10_000).filter(|_| sampler.sample()).count();
+        assert!((800..1200).contains(&hits), "{} hits", hits);
+    }
+}
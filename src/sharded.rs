@@ -0,0 +1,134 @@
+use crate::{LruCache, MemorySize, SharedLru, Simple, ValueRef};
+use std::{
+    borrow::Borrow,
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    sync::Arc,
+};
+
+/// Several independent pools, each with an equal share of the byte limit. Caches made from it
+/// route each key to one pool by its hash, so operations on different keys rarely share a lock.
+///
+/// Each shard evicts on its own, so one can be evicting while another still has room. With many
+/// keys of similar size the shards fill evenly; a few very large entries can leave the total
+/// usage well under the limit.
+pub struct ShardedLru {
+    shards: Vec<Arc<SharedLru>>,
+}
+
+impl SharedLru {
+    /// Splits `total_byte_limit` evenly across `shards` pools. See [`ShardedLru`].
+    ///
+    /// When the limit doesn't divide evenly, the first shards get one extra byte each, so the
+    /// shards' limits add up to `total_byte_limit`.
+    pub fn sharded(total_byte_limit: usize, shards: usize) -> ShardedLru {
+        let shards = shards.max(1);
+        let (share, remainder) = (total_byte_limit / shards, total_byte_limit % shards);
+        ShardedLru {
+            shards: (0..shards)
+                .map(|i| SharedLru::with_byte_limit(share + usize::from(i < remainder)))
+                .collect(),
+        }
+    }
+}
+
+impl ShardedLru {
+    /// Makes a cache with one part on each shard. Like [`SharedLru::make_cache`], its entries
+    /// compete for room with every other cache on the same shard.
+    pub fn make_cache<K, V>(&self) -> ShardedCache<K, V>
+    where
+        K: Eq + Hash + Simple,
+        V: Simple,
+    {
+        ShardedCache {
+            caches: self.shards.iter().map(SharedLru::make_cache).collect(),
+        }
+    }
+
+    pub fn shards(&self) -> &[Arc<SharedLru>] {
+        &self.shards
+    }
+}
+
+/// A cache spread over the pools of a [`ShardedLru`].
+pub struct ShardedCache<K, V> {
+    caches: Vec<LruCache<K, V>>,
+}
+
+impl<K, V> ShardedCache<K, V>
+where
+    K: MemorySize + Eq + Hash + Simple,
+    V: MemorySize + Simple,
+{
+    pub fn insert(&self, key: K, value: V)
+    where
+        K: Clone,
+    {
+        self.shard_for(&key).insert(key, value);
+    }
+
+    pub fn get<Q>(&self, k: &Q) -> Option<ValueRef<'_, K, V>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.shard_for(k).get(k)
+    }
+
+    pub fn get_or_insert(&self, k: K, insert_with: impl FnOnce() -> V) -> Option<ValueRef<'_, K, V>>
+    where
+        K: Clone,
+    {
+        self.shard_for(&k).get_or_insert(k, insert_with)
+    }
+
+    pub fn pop<Q>(&self, k: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.shard_for(k).pop(k)
+    }
+
+    fn shard_for<Q>(&self, k: &Q) -> &LruCache<K, V>
+    where
+        Q: Hash + ?Sized,
+    {
+        let mut hasher = DefaultHasher::new();
+        k.hash(&mut hasher);
+        &self.caches[hasher.finish() as usize % self.caches.len()]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn routes_keys_across_shards() {
+        let sharded = SharedLru::sharded(4000, 4);
+        let cache = sharded.make_cache();
+        for i in 0..100u32 {
+            cache.insert(i, 0u32);
+        }
+
+        for i in 0..100u32 {
+            assert!(cache.get(&i).is_some());
+        }
+        for shard in sharded.shards() {
+            assert_eq!(shard.byte_limit(), 1000);
+            assert!(shard.percent_used() > 0.);
+        }
+    }
+
+    #[test]
+    fn spreads_the_remainder() {
+        let sharded = SharedLru::sharded(10, 4);
+        let limits = sharded
+            .shards()
+            .iter()
+            .map(|shard| shard.byte_limit())
+            .collect::<Vec<_>>();
+        assert_eq!(limits, [3, 3, 2, 2]);
+    }
+}
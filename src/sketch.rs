@@ -0,0 +1,97 @@
+use std::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+
+const ROWS: usize = 4;
+
+/// A count-min sketch of recent key accesses, halved periodically so old popularity fades.
+///
+/// Counters are updated with relaxed atomics, so concurrent increments may occasionally be lost.
+/// That only makes estimates slightly low, which is fine for admission decisions.
+pub(crate) struct FrequencySketch {
+    counters: Vec<AtomicU8>,
+    width_mask: usize,
+    additions: AtomicUsize,
+    reset_at: usize,
+}
+
+impl FrequencySketch {
+    pub(crate) fn new(width: usize) -> Self {
+        let width = width.max(1).next_power_of_two();
+        FrequencySketch {
+            counters: (0..ROWS * width).map(|_| AtomicU8::new(0)).collect(),
+            width_mask: width - 1,
+            additions: AtomicUsize::new(0),
+            reset_at: width * 10,
+        }
+    }
+
+    pub(crate) fn increment(&self, hash: u64) {
+        for counter in self.counters_for(hash) {
+            let _ = counter.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| {
+                count.checked_add(1)
+            });
+        }
+
+        if self.additions.fetch_add(1, Ordering::Relaxed) + 1 == self.reset_at {
+            self.halve();
+        }
+    }
+
+    pub(crate) fn estimate(&self, hash: u64) -> u8 {
+        self.counters_for(hash)
+            .map(|counter| counter.load(Ordering::Relaxed))
+            .min()
+            .unwrap_or(0)
+    }
+
+    pub(crate) fn clear(&self) {
+        for counter in &self.counters {
+            counter.store(0, Ordering::Relaxed);
+        }
+        self.additions.store(0, Ordering::Relaxed);
+    }
+
+    fn halve(&self) {
+        for counter in &self.counters {
+            let _ = counter.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| {
+                Some(count / 2)
+            });
+        }
+        self.additions
+            .fetch_sub(self.reset_at / 2, Ordering::Relaxed);
+    }
+
+    fn counters_for(&self, hash: u64) -> impl Iterator<Item = &AtomicU8> + '_ {
+        let width = self.width_mask + 1;
+        (0..ROWS).map(move |row| {
+            // Each row takes a different 16 bits of the hash, mixed with the row number.
+            let bits = (hash >> (row * 16)) ^ (row as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+            let column =
+                (bits.wrapping_mul(0xbf58_476d_1ce4_e5b9) >> 32) as usize & self.width_mask;
+            &self.counters[row * width + column]
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn estimates_frequency_and_ages() {
+        let sketch = FrequencySketch::new(64);
+        for _ in 0..5 {
+            sketch.increment(1);
+        }
+        sketch.increment(2);
+
+        assert!(sketch.estimate(1) >= 5);
+        assert!(sketch.estimate(2) >= 1);
+        assert!(sketch.estimate(1) > sketch.estimate(2));
+
+        // Reaching 10 additions per column halves every counter.
+        for _ in 0..634 {
+            sketch.increment(2);
+        }
+        assert!(sketch.estimate(1) < 5);
+    }
+}
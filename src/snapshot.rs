@@ -0,0 +1,57 @@
+use crate::EntryId;
+
+/// Occupancy of every entry in a pool at one point in time. Values are not included.
+#[derive(Debug, Clone, Default)]
+pub struct PoolSnapshot {
+    /// Ordered from least to most recently used.
+    pub entries: Vec<EntrySnapshot>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntrySnapshot {
+    pub id: EntryId,
+    pub bytes: usize,
+    /// Position from least recently used, starting at 0. This is LRU position, not eviction
+    /// order: pinned entries are counted, and
+    /// [`Policy::LfuWithAging`](crate::Policy::LfuWithAging) evicts by frequency instead.
+    pub recency_rank: usize,
+    /// The name of the cache holding the entry, if it was made with
+    /// [`SharedLru::make_named_cache`](crate::SharedLru::make_named_cache).
+    pub cache: Option<String>,
+}
+
+/// A range of entry sizes in [`SharedLru::size_histogram`](crate::SharedLru::size_histogram),
+/// from `min` to `max` bytes inclusive. Every bucket but the first spans a power of two.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SizeBucket {
+    pub min: usize,
+    pub max: usize,
+}
+
+impl SizeBucket {
+    pub(crate) fn of(bytes: usize) -> Self {
+        match bytes {
+            0 => SizeBucket { min: 0, max: 0 },
+            _ => {
+                let min = 1 << (usize::BITS - 1 - bytes.leading_zeros());
+                SizeBucket {
+                    min,
+                    max: min + (min - 1),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_by_power_of_two() {
+        assert_eq!(SizeBucket::of(0), SizeBucket { min: 0, max: 0 });
+        assert_eq!(SizeBucket::of(1), SizeBucket { min: 1, max: 1 });
+        assert_eq!(SizeBucket::of(5), SizeBucket { min: 4, max: 7 });
+        assert_eq!(SizeBucket::of(usize::MAX).max, usize::MAX);
+    }
+}
@@ -0,0 +1,147 @@
+use crate::{Inserted, LruCache, MemorySize, SharedLru, Simple, ValueRef};
+use std::{hash::Hash, sync::Arc};
+
+/// A slower second tier for entries evicted from a [`TieredCache`], such as files on disk.
+///
+/// Methods are called without the pool lock held, but possibly from whichever thread's insert
+/// caused the eviction.
+pub trait SpillStore<K, V>: Simple {
+    fn put(&self, key: K, value: V);
+
+    fn get(&self, key: &K) -> Option<V>;
+
+    fn remove(&self, key: &K);
+}
+
+/// A cache whose evicted entries are written to a [`SpillStore`] instead of being dropped.
+///
+/// `get` falls back to the store on a miss and promotes the entry back into memory. An entry is
+/// spilled just after it leaves memory, so a `get` racing its eviction may briefly miss both tiers.
+///
+/// Entries evicted for room, rejected by [`LruCache::maintain`] or evicted with
+/// [`SharedLru::evict_id`] are spilled. [`SharedLru::clear_all`] and [`SharedLru::reset`] drop
+/// them instead, and leave what is already in the store.
+pub struct TieredCache<K, V, S> {
+    cache: LruCache<K, V>,
+    store: Arc<S>,
+}
+
+impl SharedLru {
+    /// Like [`SharedLru::make_cache`], but entries evicted for room are spilled to `store`.
+    pub fn make_tiered_cache<K, V, S>(self: &Arc<Self>, store: S) -> TieredCache<K, V, S>
+    where
+        K: Eq + Hash + Simple,
+        V: Simple,
+        S: SpillStore<K, V>,
+    {
+        let store = Arc::new(store);
+        let spill_to = Arc::clone(&store);
+        TieredCache {
+            cache: self.make_cache_with_hook(Arc::new(move |key, value| spill_to.put(key, value))),
+            store,
+        }
+    }
+}
+
+impl<K, V, S> TieredCache<K, V, S>
+where
+    K: MemorySize + Eq + Hash + Simple + Clone,
+    V: MemorySize + Simple,
+    S: SpillStore<K, V>,
+{
+    /// Inserts into memory, discarding any spilled value for `key`.
+    pub fn insert(&self, key: K, value: V) {
+        self.store.remove(&key);
+        self.cache.insert(key, value);
+    }
+
+    /// Gets the value for `key` from memory, promoting it from the store on a miss.
+    ///
+    /// A spilled value the memory tier refuses, e.g. because it is too large, stays in the store
+    /// and reads as a miss.
+    pub fn get(&self, key: &K) -> Option<ValueRef<'_, K, V>> {
+        if let Some(value) = self.cache.get(key) {
+            return Some(value);
+        }
+
+        let value = self.store.get(key)?;
+        self.store.remove(key);
+        match self.cache.insert_full(key.clone(), value) {
+            Inserted::New | Inserted::Replaced(_) => self.cache.get(key),
+            Inserted::TooLarge(value) | Inserted::Frozen(value) | Inserted::Rejected(value) => {
+                self.store.put(key.clone(), value);
+                None
+            }
+        }
+    }
+
+    /// Removes `key` from both tiers.
+    pub fn pop(&self, key: &K) -> Option<V> {
+        if let Some(value) = self.cache.pop(key) {
+            return Some(value);
+        }
+
+        let value = self.store.get(key)?;
+        self.store.remove(key);
+        Some(value)
+    }
+
+    /// The in-memory tier.
+    pub fn memory(&self) -> &LruCache<K, V> {
+        &self.cache
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{collections::HashMap, sync::Mutex};
+
+    #[derive(Default)]
+    struct MapStore(Mutex<HashMap<u8, u64>>);
+
+    impl SpillStore<u8, u64> for MapStore {
+        fn put(&self, key: u8, value: u64) {
+            self.0.lock().unwrap().insert(key, value);
+        }
+
+        fn get(&self, key: &u8) -> Option<u64> {
+            self.0.lock().unwrap().get(key).copied()
+        }
+
+        fn remove(&self, key: &u8) {
+            self.0.lock().unwrap().remove(key);
+        }
+    }
+
+    #[test]
+    fn spills_and_promotes() {
+        let shared = SharedLru::with_byte_limit(2 * (1 + 8));
+        let cache = shared.make_tiered_cache(MapStore::default());
+        cache.insert(0, 10);
+        cache.insert(1, 11);
+        cache.insert(2, 12);
+
+        assert!(cache.memory().get(&0).is_none());
+        assert_eq!(cache.store().get(&0), Some(10));
+
+        assert_eq!(cache.get(&0).as_deref(), Some(&10));
+        assert_eq!(cache.store().get(&0), None);
+        assert_eq!(cache.store().get(&1), Some(11));
+    }
+
+    #[test]
+    fn clear_all_does_not_spill() {
+        let shared = SharedLru::with_byte_limit(2 * (1 + 8));
+        let cache = shared.make_tiered_cache(MapStore::default());
+        cache.insert(0, 10);
+
+        shared.clear_all();
+        assert_eq!(cache.store().get(&0), None);
+        assert!(cache.get(&0).is_none());
+    }
+}
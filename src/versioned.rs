@@ -0,0 +1,95 @@
+use crate::{LruCache, MemorySize, SharedLru, Simple, ValueRef};
+use std::{borrow::Borrow, hash::Hash, sync::Arc};
+
+/// A cached value tagged with the version it was fetched at.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Versioned<V> {
+    pub version: u64,
+    pub value: V,
+}
+
+impl<V: MemorySize> MemorySize for Versioned<V> {
+    fn bytes(&self) -> usize {
+        std::mem::size_of::<Self>() - std::mem::size_of::<V>() + self.value.bytes()
+    }
+}
+
+/// A cache that drops values older than the version a reader asks for, so stale entries can be
+/// invalidated by bumping a version rather than flushing.
+pub struct VersionedCache<K, V> {
+    cache: LruCache<K, Versioned<V>>,
+}
+
+impl SharedLru {
+    /// Like [`SharedLru::make_cache`], but values carry a version. See [`VersionedCache`].
+    pub fn make_versioned_cache<K, V>(self: &Arc<Self>) -> VersionedCache<K, V>
+    where
+        K: Eq + Hash + Simple,
+        V: Simple,
+    {
+        VersionedCache {
+            cache: self.make_cache(),
+        }
+    }
+}
+
+impl<K, V> VersionedCache<K, V>
+where
+    K: MemorySize + Eq + Hash + Simple + Clone,
+    V: MemorySize + Simple,
+{
+    pub fn insert(&self, key: K, version: u64, value: V) {
+        self.cache.insert(key, Versioned { version, value });
+    }
+
+    /// Gets the value for `k` if its version is at least `min_version`. An older value is removed
+    /// and reads as a miss.
+    pub fn get_if_fresh<Q>(&self, k: &Q, min_version: u64) -> Option<ValueRef<'_, K, Versioned<V>>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let entry = self.cache.get(k)?;
+        if entry.version >= min_version {
+            return Some(entry);
+        }
+
+        // Drop the guard before removing, and leave a fresher value that raced in alone.
+        drop(entry);
+        self.cache.pop_if(k, |stale| stale.version < min_version);
+        None
+    }
+
+    /// The underlying cache, for operations that don't care about versions.
+    pub fn inner(&self) -> &LruCache<K, Versioned<V>> {
+        &self.cache
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stale_values_read_as_misses() {
+        let shared = SharedLru::with_byte_limit(1000);
+        let cache = shared.make_versioned_cache();
+        cache.insert(0u8, 3, String::from("three"));
+
+        assert_eq!(
+            cache.get_if_fresh(&0, 3).map(|v| v.value.clone()),
+            Some(String::from("three"))
+        );
+        assert!(cache.get_if_fresh(&0, 4).is_none());
+        assert!(cache.get_if_fresh(&0, 0).is_none());
+    }
+
+    #[test]
+    fn counts_padding_around_the_version() {
+        let value = Versioned {
+            version: 0,
+            value: 0u8,
+        };
+        assert_eq!(value.bytes(), std::mem::size_of::<Versioned<u8>>());
+    }
+}